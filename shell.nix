@@ -2,7 +2,5 @@ with import <nixpkgs> {};
 pkgs.mkShell {
   buildInputs = [
       rustup
-      inkscape # needed for prettyprinting
-      wireshark # for tshark
     ];
 }
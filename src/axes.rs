@@ -0,0 +1,191 @@
+/// Axes, ticks and labels of histograms, drawn as SVG around the element in the units of the
+/// template, so the guides don't have to be drawn by hand whenever the time range or the scale
+/// changes.
+use chrono::{DateTime, Duration, TimeZone};
+use std::fmt::Display;
+
+use svg::node::element::{Group, Line, Text};
+use svg::node::Node;
+
+/// Sizes in units of the template.
+const FONT_SIZE: f64 = 10.0;
+const TICK: f64 = 6.0;
+const STROKE_WIDTH: f64 = 0.5;
+/// Ticks closer to each other than this are left out.
+const MIN_TICK_SPACING: f64 = 12.0;
+
+/// Hours between two ticks within a day, the first one that leaves enough space is used.
+const HOUR_STEPS: [i64; 5] = [1, 2, 3, 6, 12];
+
+/// Coordinates are rounded, so the SVG doesn't get longer than necessary.
+pub fn round(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
+
+fn line(x1: f64, y1: f64, x2: f64, y2: f64) -> Line {
+    Line::new()
+        .set("x1", round(x1))
+        .set("y1", round(y1))
+        .set("x2", round(x2))
+        .set("y2", round(y2))
+}
+
+/// Text content for SVG, svg doesn't escape it by itself.
+pub fn text(content: &str) -> svg::node::Text {
+    svg::node::Text::new(escape(content))
+}
+
+/// `content` with the characters that are special in XML replaced by entities, so it can be
+/// used as text or attribute value.
+pub fn escape(content: &str) -> String {
+    content
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn label(x: f64, y: f64, anchor: &str, content: String) -> Text {
+    Text::new()
+        .set("x", round(x))
+        .set("y", round(y))
+        .set("text-anchor", anchor)
+        .add(text(&content))
+}
+
+fn style(g: Group) -> Group {
+    g.set("stroke", "black")
+        .set("stroke-width", STROKE_WIDTH)
+        .set("font-family", "sans-serif")
+        .set("font-size", FONT_SIZE)
+}
+
+/// Time axis below an element of `width`×`height` that shows the time from `start` to `end`:
+/// long ticks at midnight, with the date of each day below it, and short ticks every few hours,
+/// as far as there is space for them. Days begin at midnight in the time zone of `start`.
+pub fn time_axis<Tz>(start: &DateTime<Tz>, end: &DateTime<Tz>, width: f64, height: f64) -> Group
+where
+    Tz: TimeZone,
+    Tz::Offset: Display,
+{
+    let mut g = style(Group::new());
+    g.append(line(0.0, height, width, height));
+    let seconds = |t: &DateTime<Tz>| (t.clone() - start.clone()).num_milliseconds() as f64 / 1e3;
+    let total = seconds(end);
+    if total <= 0.0 {
+        return g;
+    }
+    let x = |t: &DateTime<Tz>| width * seconds(t) / total;
+    let tz = start.timezone();
+    let local = |date: chrono::NaiveDate, hour: u32| {
+        tz.from_local_datetime(&date.and_hms_opt(hour, 0, 0).unwrap())
+            .earliest()
+    };
+    let hour_step = HOUR_STEPS
+        .iter()
+        .cloned()
+        .find(|&h| width * (h * 3600) as f64 / total >= MIN_TICK_SPACING);
+
+    let mut date = start.date_naive();
+    let last = end.date_naive();
+    while date <= last {
+        let next = date + Duration::days(1);
+        let day_start = local(date, 0).filter(|t| t >= start);
+        if let Some(ref t) = day_start {
+            if t <= end {
+                g.append(line(x(t), height, x(t), height + 2.0 * TICK));
+            }
+        }
+        if let Some(step) = hour_step {
+            for hour in (step..24).step_by(step as usize) {
+                if let Some(t) = local(date, hour as u32).filter(|t| t >= start && t <= end) {
+                    g.append(line(x(&t), height, x(&t), height + TICK));
+                }
+            }
+        }
+        // the date in the middle of the part of the day that is shown
+        let from = day_start.map(|t| x(&t)).unwrap_or(0.0);
+        let to = local(next, 0)
+            .filter(|t| t <= end)
+            .map(|t| x(&t))
+            .unwrap_or(width);
+        if to - from >= 3.0 * FONT_SIZE {
+            g.append(
+                label(
+                    (from + to) / 2.0,
+                    height + 2.0 * TICK + FONT_SIZE,
+                    "middle",
+                    date.format("%d.%m.").to_string(),
+                )
+                .set("stroke", "none"),
+            );
+        }
+        date = next;
+    }
+    g
+}
+
+/// Value axis left of an element of height `height`, with a tick and label for each
+/// `(y, label)`. Ticks that are too close to the one before are left out.
+pub fn value_axis(ticks: &[(f64, String)], height: f64) -> Group {
+    let mut g = style(Group::new());
+    g.append(line(0.0, 0.0, 0.0, height));
+    let mut previous: Option<f64> = None;
+    for &(y, ref text) in ticks {
+        if y < 0.0 || y > height || previous.is_some_and(|p| (p - y).abs() < MIN_TICK_SPACING) {
+            continue;
+        }
+        previous = Some(y);
+        g.append(line(-TICK, y, 0.0, y));
+        g.append(
+            label(-TICK - 2.0, y + FONT_SIZE / 3.0, "end", text.clone()).set("stroke", "none"),
+        );
+    }
+    g
+}
+
+/// Tick values from 0 to `top`, at a distance of 1, 2 or 5 times a power of ten, so that there
+/// are about `n` of them.
+pub fn linear_ticks(top: f64, n: usize) -> Vec<f64> {
+    if top <= 0.0 {
+        return vec![0.0];
+    }
+    let rough = top / n as f64;
+    let magnitude = 10f64.powf(rough.log10().floor());
+    let step = [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|m| m * magnitude)
+        .find(|&s| s >= rough)
+        .unwrap_or(10.0 * magnitude);
+    (0..)
+        .map(|i| i as f64 * step)
+        .take_while(|&v| v <= top * (1.0 + 1e-9))
+        .collect()
+}
+
+/// Powers of ten from `bottom` to `top`.
+pub fn log_ticks(bottom: f64, top: f64) -> Vec<f64> {
+    if bottom <= 0.0 || top < bottom {
+        return Vec::new();
+    }
+    let first = bottom.log10().ceil() as i32;
+    let last = top.log10().floor() as i32;
+    (first..=last).map(|e| 10f64.powi(e)).collect()
+}
+
+/// `v` with an SI prefix and `unit`, e.g. "20 kbit/s".
+pub fn si_label(v: f64, unit: &str) -> String {
+    if v == 0.0 {
+        return "0".to_string();
+    }
+    let prefixes = [(1e12, "T"), (1e9, "G"), (1e6, "M"), (1e3, "k")];
+    let (divisor, prefix) = prefixes
+        .iter()
+        .cloned()
+        .find(|&(d, _)| v.abs() >= d)
+        .unwrap_or((1.0, ""));
+    let number = format!("{:.2}", v / divisor);
+    let number = number.trim_end_matches('0').trim_end_matches('.');
+    format!("{} {}{}", number, prefix, unit)
+}
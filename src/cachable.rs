@@ -1,17 +1,500 @@
 /// Dumb name for a trait, that is implemented by any piece of image data, that was generated from
 /// the raw_data.
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use error::{Error, Result};
+use histogram::HistogramData;
+use manifest::{Entry, InputFile, Manifest};
+
+use sha2::{Digest, Sha256};
+
+use svg::node::element::Group;
+
+/// Where cached images and data live, and whether existing entries may be used.
+pub struct Cache {
+    pub dir: PathBuf,
+    /// Entries that weren't written during this run count as missing, so everything gets
+    /// computed again, but only once per run.
+    force: bool,
+    /// the entries written during this run, see `record`
+    rewritten: Mutex<HashSet<PathBuf>>,
+    /// loaded on first use
+    manifest: Mutex<Option<Manifest>>,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Cache {
+        Cache {
+            dir,
+            force: false,
+            rewritten: Mutex::new(HashSet::new()),
+            manifest: Mutex::new(None),
+        }
+    }
+
+    fn with_manifest<R>(&self, f: impl FnOnce(&mut Manifest) -> R) -> R {
+        let mut manifest = self.manifest.lock().unwrap();
+        if manifest.is_none() {
+            *manifest = Some(Manifest::load(&self.dir).unwrap_or_else(|e| {
+                println!("{}, starting a new manifest", e);
+                Manifest::default()
+            }));
+        }
+        f(manifest.as_mut().unwrap())
+    }
+
+    /// Write the manifest back, if it was used.
+    pub fn save_manifest(&self) -> Result<()> {
+        match *self.manifest.lock().unwrap() {
+            Some(ref m) => m.save(&self.dir),
+            None => Ok(()),
+        }
+    }
+
+    /// Remember what the entry at `path`, that was just written, was computed from.
+    pub fn record(&self, path: &Path, key: &CacheKey) {
+        self.rewritten.lock().unwrap().insert(path.to_path_buf());
+        let entry = Entry {
+            kind: key.kind.clone(),
+            elements: BTreeSet::new(),
+            parameters: key.parameters.clone(),
+            inputs: key.inputs.clone(),
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            size: std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        };
+        self.with_manifest(|m| m.entries.insert(file_name(path), entry));
+    }
+
+    /// Set the ids of the elements that use the entry at `path`.
+    pub fn set_elements(&self, path: &Path, elements: BTreeSet<String>) {
+        self.with_manifest(|m| {
+            if let Some(e) = m.entries.get_mut(&file_name(path)) {
+                e.elements = elements;
+            }
+        });
+    }
+
+    /// What the manifest knows about the entry at `path`.
+    pub fn manifest_entry(&self, path: &Path) -> Option<Entry> {
+        self.with_manifest(|m| m.entries.get(&file_name(path)).cloned())
+    }
+
+    /// Recompute every entry that is used from now on.
+    pub fn force_recomputation(&mut self) {
+        self.force = true;
+    }
+
+    pub fn path(&self, file_name: &str) -> PathBuf {
+        self.dir.join(file_name)
+    }
+
+    /// True if the entry exists and may be used.
+    pub fn is_fresh(&self, path: &Path) -> bool {
+        // compare the paths and not the modification times, which may be only as exact as a
+        // second or two
+        path.exists() && (!self.force || self.rewritten.lock().unwrap().contains(path))
+    }
+
+    /// All cached images and data, sorted by file name.
+    pub fn entries(&self) -> std::io::Result<Vec<PathBuf>> {
+        let mut res = Vec::new();
+        for e in std::fs::read_dir(&self.dir)? {
+            let path = e?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("png") | Some("vec") | Some("scan") => res.push(path),
+                _ => {}
+            }
+        }
+        res.sort();
+        Ok(res)
+    }
+
+    /// Remove all cached images and data.
+    pub fn clear(&self) -> std::io::Result<usize> {
+        let entries = self.entries()?;
+        for e in &entries {
+            std::fs::remove_file(e)?;
+        }
+        self.with_manifest(|m| m.entries.clear());
+        Ok(entries.len())
+    }
+
+    /// Problems of the cache: entries that are missing, have the wrong size, whose inputs changed
+    /// since, or that are not in the manifest.
+    pub fn verify(&self) -> std::io::Result<Vec<(PathBuf, String)>> {
+        let files = self.entries()?;
+        let mut res = Vec::new();
+        self.with_manifest(|m| {
+            for (name, e) in &m.entries {
+                let path = self.path(name);
+                match std::fs::metadata(&path) {
+                    Err(_) => res.push((path, "missing".to_string())),
+                    Ok(ref meta) if meta.len() != e.size => res.push((
+                        path,
+                        format!("has {} bytes instead of {}", meta.len(), e.size),
+                    )),
+                    Ok(_) => {
+                        for i in e.inputs.iter().filter(|i| !i.unchanged()) {
+                            res.push((path.clone(), format!("{} changed", i.path.display())));
+                        }
+                    }
+                }
+            }
+            for f in files {
+                if !m.entries.contains_key(&file_name(&f)) {
+                    res.push((f, "not in the manifest".to_string()));
+                }
+            }
+        });
+        res.sort();
+        Ok(res)
+    }
+
+    /// Remove all entries that are not in `used`, or only list them with `dry_run`.
+    pub fn gc(&self, used: &HashSet<PathBuf>, dry_run: bool) -> std::io::Result<Vec<PathBuf>> {
+        let used: HashSet<String> = used.iter().map(|p| file_name(p)).collect();
+        let unused: Vec<PathBuf> = self
+            .entries()?
+            .into_iter()
+            .filter(|e| !used.contains(&file_name(e)))
+            .collect();
+        if !dry_run {
+            for e in &unused {
+                std::fs::remove_file(e)?;
+            }
+            self.with_manifest(|m| m.entries.retain(|name, _| used.contains(name)));
+        }
+        Ok(unused)
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Name of a cache entry, that only depends on what the entry is computed from: the parameters
+/// and the input files. Unlike `DefaultHasher`, SHA-256 doesn't change between Rust releases, so
+/// the cache survives toolchain upgrades.
+pub struct CacheKey {
+    hasher: Sha256,
+    kind: String,
+    parameters: Vec<(String, String)>,
+    inputs: Vec<InputFile>,
+}
+
+impl CacheKey {
+    /// Start a key for entries of type `kind`. Change `kind` whenever the computation changes,
+    /// so old entries aren't used anymore.
+    pub fn new(kind: &str) -> CacheKey {
+        let mut key = CacheKey {
+            hasher: Sha256::new(),
+            kind: kind.to_string(),
+            parameters: Vec::new(),
+            inputs: Vec::new(),
+        };
+        key.add("kind", kind);
+        key
+    }
+
+    /// Add a parameter. Every value is prefixed with its name and length, so different
+    /// parameters can't produce the same key.
+    pub fn add<T: Display + ?Sized>(&mut self, name: &str, value: &T) {
+        let value = value.to_string();
+        self.hasher
+            .update(format!("{}:{}:{}\n", name, value.len(), value));
+        if name != "kind" {
+            self.parameters.push((name.to_string(), value));
+        }
+    }
+
+    /// Add an input file by its path, size and modification time, so that replacing it
+    /// invalidates the entry, without reading the whole file on every run. Files that were
+    /// copied together often have the same size and time, so the path is needed to tell them
+    /// apart.
+    pub fn add_input(&mut self, name: &str, path: &Path) {
+        let input = InputFile::current(path);
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        match (input.size, input.modified) {
+            (Some(size), Some(modified)) => self.add(
+                name,
+                &format!(
+                    "{}, {} bytes, modified {}",
+                    canonical.display(),
+                    size,
+                    modified
+                ),
+            ),
+            _ => self.add(name, &format!("{}, missing", canonical.display())),
+        }
+        self.inputs.push(input);
+    }
+
+    /// The key as hex string, the first 128 bits are plenty to tell entries apart.
+    pub fn hex(&self) -> String {
+        self.hasher.clone().finalize()[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Where the image with `key` is cached.
+fn png_path(cache: &Cache, key: &CacheKey) -> PathBuf {
+    cache.path(&format!("{}.png", key.hex()))
+}
 
 pub trait CachableData {
+    /// What is computed and cached.
+    type Data;
     /// Return the data, but first if is already in the cache.
-    fn data_cached(&self) -> Vec<u64>;
+    fn data_cached(&self, cache: &Cache) -> Result<Self::Data>;
     /// Return the data, but force recomputation.
-    fn data_uncached(&self) -> Vec<u64>;
+    fn data_uncached(&self) -> Result<Self::Data>;
 }
 
-pub trait CachablePNG {
+/// Elements are drawn on several threads at the same time, see `parallel`.
+pub trait CachablePNG: Send + Sync {
+    /// What the image is computed from, it is cached under this key. `None` if there is no image
+    /// to cache.
+    fn cache_key(&self) -> Option<CacheKey> {
+        None
+    }
     /// Return the image, but first if is already in the cache.
-    fn png_cached(&self) -> Result<PathBuf, &str>;
+    fn png_cached(&self, cache: &Cache) -> Result<PathBuf> {
+        let key = self.cache_key().ok_or_else(|| {
+            Error::Render("The element can only be drawn as vector graphics".to_string())
+        })?;
+        let path = png_path(cache, &key);
+        if cache.is_fresh(&path) {
+            println!("Cache hit for {}", &path.to_str().unwrap());
+            Ok(path)
+        } else {
+            println!("Cache miss for {}", &path.to_str().unwrap());
+            let path = self.png_uncached(cache, path)?;
+            cache.record(&path, &key);
+            Ok(path)
+        }
+    }
     /// Return the image, but force recomputation.
-    fn png_uncached(&self, path: PathBuf) -> Result<PathBuf, &str>;
+    fn png_uncached(&self, cache: &Cache, path: PathBuf) -> Result<PathBuf>;
+    /// The image as SVG elements instead of pixels, with one user unit per pixel of the PNG,
+    /// together with the size of the PNG. `None` if there is only the raster version.
+    fn svg(&self, _cache: &Cache) -> Result<Option<(Group, u32, u32)>> {
+        Ok(None)
+    }
+    /// The cache entries the element uses, whether they exist yet or not: the image and the
+    /// histogram data it is made of.
+    fn cache_paths(&self, cache: &Cache) -> Vec<PathBuf> {
+        let image = self.cache_key().map(|key| png_path(cache, &key));
+        let data = self
+            .histogram_data()
+            .into_iter()
+            .map(|d| d.cache_path(cache));
+        image.into_iter().chain(data).collect()
+    }
+    /// True if there is no PNG version, so `svg` is used even without `--vector`.
+    fn vector_only(&self) -> bool {
+        false
+    }
+    /// Vector graphics drawn on top of the element, like axes, whether it is drawn as PNG or SVG.
+    /// The origin is the top left corner of the element and units are those of the template,
+    /// where the element is `width`×`height` big. It may reach beyond the element.
+    fn overlay(&self, _cache: &Cache, _width: f64, _height: f64) -> Result<Option<Group>> {
+        Ok(None)
+    }
+    /// Called with the size of the element's `<image>` in the template, before anything is
+    /// drawn, so the image can be made to fit.
+    fn fit(&mut self, _width: f64, _height: f64) {}
+    /// The histogram data the image is made of, so it can be computed in batches beforehand.
+    fn histogram_data(&self) -> Vec<&HistogramData> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use testing::scratch_dir;
+
+    fn key(parameters: &[(&str, &str)]) -> String {
+        let mut key = CacheKey::new("test");
+        for &(name, value) in parameters {
+            key.add(name, value);
+        }
+        key.hex()
+    }
+
+    #[test]
+    fn stable_keys() {
+        // SHA-256 of "kind:4:test\nwidth:3:100\n", the same on every run and toolchain
+        assert_eq!(key(&[("width", "100")]), "95a630c8c978d6b895730685493c0de9");
+        assert_eq!(key(&[("width", "100")]), key(&[("width", "100")]));
+    }
+
+    #[test]
+    fn parameters_change_the_key() {
+        let base = key(&[("width", "100"), ("height", "50")]);
+        assert_ne!(base, key(&[("width", "101"), ("height", "50")]));
+        assert_ne!(
+            base,
+            key(&[("width", "100"), ("height", "50"), ("gray", "0")])
+        );
+        assert_ne!(base, key(&[("height", "50"), ("width", "100")]));
+        let mut other_kind = CacheKey::new("other");
+        other_kind.add("width", "100");
+        other_kind.add("height", "50");
+        assert_ne!(base, other_kind.hex());
+    }
+
+    #[test]
+    fn framing_prevents_collisions() {
+        // the same bytes, concatenated, but split differently between names and values
+        assert_ne!(key(&[("a", "bc")]), key(&[("ab", "c")]));
+        assert_ne!(key(&[("a", "1"), ("b", "2")]), key(&[("a", "1\nb:1:2")]));
+        assert_ne!(key(&[("a", "")]), key(&[("a:0:", "")]));
+        assert_ne!(key(&[("filter", "")]), key(&[]));
+    }
+
+    #[test]
+    fn inputs_change_the_key() {
+        let dir = scratch_dir("cache-key");
+        let path = dir.join("capture.pcapng");
+        let key = |path: &Path| {
+            let mut key = CacheKey::new("test");
+            key.add_input("path", path);
+            key.hex()
+        };
+        let missing = key(&path);
+        fs::write(&path, b"1234").unwrap();
+        let written = key(&path);
+        assert_ne!(missing, written);
+        assert_eq!(written, key(&path));
+        // an input at another path with the same size and time
+        let copy = dir.join("copy.pcapng");
+        fs::copy(&path, &copy).unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&copy)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_ne!(written, key(&copy));
+        // same size, other time
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        let touched = key(&path);
+        assert_ne!(written, touched);
+        // other size
+        fs::write(&path, b"12345").unwrap();
+        assert_ne!(touched, key(&path));
+    }
+
+    /// A cache with the entries `a.png`, computed from `input`, and `b.vec`, that are recorded
+    /// in the saved manifest, and `c.scan`, that isn't.
+    fn filled_cache(name: &str) -> (Cache, PathBuf) {
+        let dir = scratch_dir(name);
+        let input = dir.join("input.pcapng");
+        fs::write(&input, b"packets").unwrap();
+        let cache = Cache::new(dir.join("cache"));
+        fs::create_dir(&cache.dir).unwrap();
+        for name in &["a.png", "b.vec"] {
+            let path = cache.path(name);
+            fs::write(&path, name).unwrap();
+            let mut key = CacheKey::new("test");
+            key.add_input("input", &input);
+            cache.record(&path, &key);
+        }
+        fs::write(cache.path("c.scan"), b"c").unwrap();
+        fs::write(cache.path("notes.txt"), b"not an entry").unwrap();
+        cache.save_manifest().unwrap();
+        (Cache::new(cache.dir.clone()), input)
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<String> {
+        paths.iter().map(|p| file_name(p)).collect()
+    }
+
+    #[test]
+    fn manifest_is_saved() {
+        let (cache, input) = filled_cache("cache-manifest");
+        let entry = cache.manifest_entry(&cache.path("a.png")).unwrap();
+        assert_eq!(entry.kind, "test");
+        assert_eq!(entry.size, 5);
+        assert_eq!(entry.inputs, vec![InputFile::current(&input)]);
+        assert!(cache.manifest_entry(&cache.path("c.scan")).is_none());
+        assert_eq!(
+            names(&cache.entries().unwrap()),
+            vec!["a.png", "b.vec", "c.scan"]
+        );
+    }
+
+    #[test]
+    fn verify_cache() {
+        let (cache, input) = filled_cache("cache-verify");
+        let problems = |cache: &Cache| -> Vec<(String, String)> {
+            let res = cache.verify().unwrap();
+            res.into_iter().map(|(p, e)| (file_name(&p), e)).collect()
+        };
+        assert_eq!(
+            problems(&cache),
+            vec![("c.scan".to_string(), "not in the manifest".to_string())]
+        );
+        fs::write(&input, b"other packets").unwrap();
+        fs::write(cache.path("b.vec"), b"longer").unwrap();
+        fs::remove_file(cache.path("c.scan")).unwrap();
+        let changed = format!("{} changed", input.display());
+        assert_eq!(
+            problems(&cache),
+            vec![
+                ("a.png".to_string(), changed),
+                ("b.vec".to_string(), "has 6 bytes instead of 5".to_string()),
+            ]
+        );
+        fs::remove_file(cache.path("a.png")).unwrap();
+        assert_eq!(
+            problems(&cache)[0],
+            ("a.png".to_string(), "missing".to_string())
+        );
+    }
+
+    #[test]
+    fn gc_dry_run() {
+        let (cache, _) = filled_cache("cache-gc-dry-run");
+        let used: HashSet<PathBuf> = vec![cache.path("a.png")].into_iter().collect();
+        let unused = cache.gc(&used, true).unwrap();
+        assert_eq!(names(&unused), vec!["b.vec", "c.scan"]);
+        assert_eq!(cache.entries().unwrap().len(), 3);
+        assert!(cache.manifest_entry(&cache.path("b.vec")).is_some());
+    }
+
+    #[test]
+    fn gc_removes_unused_entries() {
+        let (cache, _) = filled_cache("cache-gc");
+        let used: HashSet<PathBuf> = vec![cache.path("a.png")].into_iter().collect();
+        let unused = cache.gc(&used, false).unwrap();
+        assert_eq!(names(&unused), vec!["b.vec", "c.scan"]);
+        assert_eq!(names(&cache.entries().unwrap()), vec!["a.png"]);
+        assert!(cache.path("notes.txt").exists());
+        cache.save_manifest().unwrap();
+        let cache = Cache::new(cache.dir.clone());
+        assert!(cache.manifest_entry(&cache.path("a.png")).is_some());
+        assert!(cache.manifest_entry(&cache.path("b.vec")).is_none());
+        assert!(cache.verify().unwrap().is_empty());
+    }
 }
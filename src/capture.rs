@@ -0,0 +1,116 @@
+/// Opening capture files, which may be compressed with xz or gzip, e.g. `week.pcapng.xz`. The
+/// compression is detected by the magic bytes at the start of the file, and the decompression is
+/// streamed, nothing is written to disk.
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use error::{Error, Result};
+
+use flate2::bufread::MultiGzDecoder;
+use pcarp::Capture;
+use xz2::bufread::XzDecoder;
+
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// File name extensions of compressed captures that we can read.
+pub const COMPRESSED_EXTENSIONS: &[&str] = &["xz", "gz"];
+
+pub fn open(path: &Path) -> Result<Capture<Box<dyn Read>>> {
+    let file = File::open(path).map_err(Error::io(path))?;
+    let mut file = BufReader::new(file);
+    let magic = file.fill_buf().map_err(Error::io(path))?;
+    let reader: Box<dyn Read> = if magic.starts_with(XZ_MAGIC) {
+        // archives may consist of several concatenated streams
+        Box::new(XzDecoder::new_multi_decoder(file))
+    } else if magic.starts_with(GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Capture::new(reader).map_err(capture_error(path))
+}
+
+/// Wrap an error of pcarp together with the capture it happened in.
+pub fn capture_error(path: &Path) -> impl Fn(pcarp::Error) -> Error {
+    let path = path.to_path_buf();
+    move |e| Error::Capture(path.clone(), e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+    use testing::{pcapng, scratch_dir};
+    use xz2::write::XzEncoder;
+
+    fn capture(n: u8) -> Vec<u8> {
+        let packets: Vec<Vec<u8>> = (0..n).map(|i| vec![i; 60 + i as usize]).collect();
+        let packets: Vec<(u64, &[u8])> = packets
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (i as u64 * 1_000_000, &p[..]))
+            .collect();
+        pcapng(1, &packets)
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut xz = XzEncoder::new(Vec::new(), 6);
+        xz.write_all(data).unwrap();
+        xz.finish().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(data).unwrap();
+        gz.finish().unwrap()
+    }
+
+    /// The packets of the file written with `bytes`, whatever its name.
+    fn packets(name: &str, bytes: &[u8]) -> Vec<Vec<u8>> {
+        let path = scratch_dir(&format!("capture-{}", name)).join("capture");
+        fs::write(&path, bytes).unwrap();
+        let mut capture = open(&path).unwrap();
+        let mut res = Vec::new();
+        while let Some(pkt) = capture.next() {
+            res.push(pkt.unwrap().data.to_vec());
+        }
+        res
+    }
+
+    #[test]
+    fn compression_by_magic_bytes() {
+        let plain = packets("plain", &capture(3));
+        assert_eq!(plain.len(), 3);
+        assert_eq!(plain[2], vec![2; 62]);
+        assert_eq!(packets("xz", &xz(&capture(3))), plain);
+        assert_eq!(packets("gzip", &gzip(&capture(3))), plain);
+    }
+
+    #[test]
+    fn concatenated_streams() {
+        // a capture that was compressed in two parts, split in the middle of a block
+        let data = capture(3);
+        let (start, end) = data.split_at(100);
+        let plain = packets("plain-split", &data);
+        assert_eq!(packets("xz-split", &[xz(start), xz(end)].concat()), plain);
+        assert_eq!(
+            packets("gzip-split", &[gzip(start), gzip(end)].concat()),
+            plain
+        );
+    }
+
+    #[test]
+    fn garbage() {
+        let path = scratch_dir("capture-garbage").join("capture.pcapng");
+        fs::write(&path, b"not a capture at all").unwrap();
+        assert!(open(&path).is_err());
+        fs::write(&path, xz(b"not a capture at all")).unwrap();
+        assert!(open(&path).is_err());
+        assert!(open(&path.with_extension("missing")).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+/// All captures of a device read as one: the packets of several files are merged in the order of
+/// their timestamps, and packets that were captured twice, because the captures overlap, are
+/// counted only once. Captures overlap e.g. if a capture was restarted before the old one was
+/// stopped, or if the same traffic was captured on the router and on the device.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use pcarp::{Capture, LinkType};
+
+use capture;
+use error::Result;
+
+/// The captures of one device.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CaptureSet {
+    /// name of the device, for messages
+    pub device: String,
+    pub files: Vec<PathBuf>,
+    /// packets with the same bytes from different files, captured at most this long after each
+    /// other, are the same packet, `None` to keep all packets
+    pub dedup_window: Option<Duration>,
+    /// if a file has no packets for longer than this, the capture wasn't running in between,
+    /// e.g. because the device was asleep. `None` if every file covers the whole time from its
+    /// first to its last packet.
+    pub max_silence: Option<Duration>,
+}
+
+/// Time from `.0` to `.1`, both included.
+pub type Interval = (SystemTime, SystemTime);
+
+/// A packet of one of the files of a `CaptureSet`.
+pub struct Frame {
+    pub timestamp: Option<SystemTime>,
+    pub link_type: Option<LinkType>,
+    pub data: Vec<u8>,
+    /// index of the file in `CaptureSet::files`
+    pub file: usize,
+}
+
+impl CaptureSet {
+    /// All files, opened to be read in the order of the timestamps of their packets.
+    pub fn open(&self) -> Result<Merged> {
+        let mut captures = Vec::new();
+        for path in &self.files {
+            captures.push(capture::open(path)?);
+        }
+        Ok(Merged {
+            set: self.clone(),
+            heads: captures.iter().map(|_| None).collect(),
+            captures,
+            recent: VecDeque::new(),
+            last_seen: HashMap::new(),
+            covered: self.files.iter().map(|_| Vec::new()).collect(),
+            duplicates: 0,
+        })
+    }
+
+    /// The files, separated by commas.
+    pub fn describe(&self) -> String {
+        let names: Vec<String> = self.files.iter().map(|f| f.display().to_string()).collect();
+        names.join(", ")
+    }
+}
+
+/// Iterator over the packets of a `CaptureSet`, without the duplicates.
+pub struct Merged {
+    set: CaptureSet,
+    captures: Vec<Capture<Box<dyn Read>>>,
+    /// the next packet of every file, `None` if it wasn't read yet or the file is done
+    heads: Vec<Option<Frame>>,
+    /// hashes of the packets within the dedup window, oldest first
+    recent: VecDeque<(SystemTime, u64)>,
+    /// time and file of the latest packet with a hash
+    last_seen: HashMap<u64, (SystemTime, usize)>,
+    /// when each file was capturing, judged by the timestamps of its packets. pcarp doesn't give
+    /// us the interface statistics blocks, so their start and end times can't be used.
+    covered: Vec<Vec<Interval>>,
+    /// packets left out, because they were already there in another file
+    pub duplicates: u64,
+}
+
+impl Merged {
+    /// Read the next packet of file `i` into its head, if it isn't there yet.
+    fn fill(&mut self, i: usize) -> Result<()> {
+        if self.heads[i].is_some() {
+            return Ok(());
+        }
+        let path = &self.set.files[i];
+        let frame = match self.captures[i].next() {
+            Some(pkt) => {
+                let pkt = pkt.map_err(capture::capture_error(path))?;
+                Frame {
+                    timestamp: pkt.timestamp,
+                    link_type: pkt.interface.map(|i| i.link_type),
+                    data: pkt.data.to_vec(),
+                    file: i,
+                }
+            }
+            None => return Ok(()),
+        };
+        if let Some(t) = frame.timestamp {
+            let max_silence = self.set.max_silence;
+            let covered = &mut self.covered[i];
+            match covered.last_mut() {
+                Some(&mut (ref mut from, ref mut to))
+                    if max_silence.is_none_or(|s| t <= *to + s) =>
+                {
+                    // packets may be slightly out of order
+                    *from = (*from).min(t);
+                    *to = (*to).max(t);
+                }
+                _ => covered.push((t, t)),
+            }
+        }
+        self.heads[i] = Some(frame);
+        Ok(())
+    }
+
+    /// True if the same bytes came from another file shortly before.
+    fn is_duplicate(&mut self, frame: &Frame) -> bool {
+        let (window, time) = match (self.set.dedup_window, frame.timestamp) {
+            (Some(w), Some(t)) => (w, t),
+            _ => return false,
+        };
+        while let Some(&(t, hash)) = self.recent.front() {
+            if t + window >= time {
+                break;
+            }
+            self.recent.pop_front();
+            if self
+                .last_seen
+                .get(&hash)
+                .is_some_and(|&(seen, _)| seen == t)
+            {
+                self.last_seen.remove(&hash);
+            }
+        }
+        let mut hasher = DefaultHasher::new();
+        frame.data.hash(&mut hasher);
+        let hash = hasher.finish();
+        if let Some(&(_, file)) = self.last_seen.get(&hash) {
+            if file != frame.file {
+                return true;
+            }
+        }
+        self.recent.push_back((time, hash));
+        self.last_seen.insert(hash, (time, frame.file));
+        false
+    }
+
+    /// When any of the files was capturing. Only complete once all packets are read.
+    pub fn coverage(&self) -> Vec<Interval> {
+        union(self.covered.iter().flatten().cloned().collect())
+    }
+}
+
+impl Iterator for Merged {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Result<Frame>> {
+        loop {
+            for i in 0..self.heads.len() {
+                if let Err(e) = self.fill(i) {
+                    return Some(Err(e));
+                }
+            }
+            // packets without timestamp first, they can't be sorted in
+            let next = self
+                .heads
+                .iter()
+                .enumerate()
+                .filter_map(|(i, h)| h.as_ref().map(|f| (f.timestamp, i)))
+                .min()
+                .map(|(_, i)| i)?;
+            let frame = self.heads[next].take().expect("the head was just found");
+            if self.is_duplicate(&frame) {
+                self.duplicates += 1;
+                continue;
+            }
+            return Some(Ok(frame));
+        }
+    }
+}
+
+/// The same time as all of `intervals`, sorted and without overlaps.
+pub fn union(mut intervals: Vec<Interval>) -> Vec<Interval> {
+    intervals.sort();
+    let mut res: Vec<Interval> = Vec::new();
+    for (start, end) in intervals {
+        match res.last_mut() {
+            Some(&mut (_, ref mut to)) if start <= *to => *to = (*to).max(end),
+            _ => res.push((start, end)),
+        }
+    }
+    res
+}
+
+/// The time spans between the given `intervals` in which none of them is, from the beginning of
+/// the first to the end of the last one.
+pub fn gaps(intervals: Vec<Interval>) -> Vec<Interval> {
+    union(intervals)
+        .windows(2)
+        .map(|w| (w[0].1, w[1].0))
+        .collect()
+}
+
+/// How much of the time from `start` to `end` the sorted, not overlapping `intervals` cover,
+/// from 0 to 1.
+pub fn fraction(intervals: &[Interval], start: SystemTime, end: SystemTime) -> f64 {
+    let whole = match end.duration_since(start) {
+        Ok(d) if d > Duration::ZERO => d.as_secs_f64(),
+        _ => return 0.0,
+    };
+    let covered: f64 = intervals
+        .iter()
+        .filter_map(|&(from, to)| to.min(end).duration_since(from.max(start)).ok())
+        .map(|d| d.as_secs_f64())
+        .sum();
+    covered / whole
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn interval(from: u64, to: u64) -> Interval {
+        (at(from), at(to))
+    }
+
+    #[test]
+    fn union_of_intervals() {
+        assert_eq!(union(Vec::new()), Vec::new());
+        // unsorted, nested and touching ones are merged
+        assert_eq!(
+            union(vec![
+                interval(20, 30),
+                interval(0, 10),
+                interval(2, 5),
+                interval(10, 12),
+                interval(25, 40),
+            ]),
+            vec![interval(0, 12), interval(20, 40)]
+        );
+    }
+
+    #[test]
+    fn gaps_between_intervals() {
+        assert_eq!(gaps(vec![interval(0, 10)]), Vec::new());
+        assert_eq!(
+            gaps(vec![interval(50, 60), interval(0, 10), interval(5, 20)]),
+            vec![interval(20, 50)]
+        );
+        // touching intervals leave no gap
+        assert_eq!(gaps(vec![interval(0, 10), interval(10, 20)]), Vec::new());
+    }
+
+    #[test]
+    fn covered_fraction() {
+        let covered = vec![interval(0, 10), interval(20, 30)];
+        assert_eq!(fraction(&covered, at(0), at(40)), 0.5);
+        // only the part within the window counts
+        assert_eq!(fraction(&covered, at(5), at(25)), 0.5);
+        assert_eq!(fraction(&covered, at(40), at(50)), 0.0);
+        assert_eq!(fraction(&covered, at(10), at(10)), 0.0);
+        assert_eq!(fraction(&covered, at(10), at(0)), 0.0);
+    }
+}
@@ -0,0 +1,1017 @@
+/// Poster description, read from a TOML file, that says which elements of the template get
+/// filled with what. See `poster.toml` for an example.
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+
+use cachable::Cache;
+use capture::COMPRESSED_EXTENSIONS;
+use capture_set::CaptureSet;
+use datagram::{Datagram, Legend, PacketSelection, Palette};
+use error::{Error, Result};
+use filter::Filter;
+use histogram::{Histogram, HistogramData, Metric, Scale, DEFAULT_HEIGHT};
+use packet::FieldKind;
+use plakat::{ImageMode, Plakat, Unavailable};
+use scan::{self, Rounding, Span};
+use stacked::{exclusive_filter, Layer, StackedHistogram};
+
+#[derive(Debug, Deserialize)]
+pub struct PosterConfig {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default = "default_template")]
+    pub template: PathBuf,
+    /// where the filled template is written to, the template itself is never changed
+    #[serde(default = "default_output")]
+    pub output: PathBuf,
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: PathBuf,
+    /// "link" to the images in the cache or "inline" them into the output
+    #[serde(default = "default_images")]
+    pub images: ImageMode,
+    /// draw elements as SVG instead of PNG, where they support it
+    #[serde(default)]
+    pub vector: bool,
+    /// where datagrams without an explicit `path` are looked up
+    #[serde(default = "default_raw_data")]
+    pub raw_data: PathBuf,
+    #[serde(default)]
+    pub pdf: PdfConfig,
+    pub timeline: Timeline,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub histograms: Vec<HistogramConfig>,
+    #[serde(default)]
+    pub stacked_histograms: Vec<StackedConfig>,
+    #[serde(default)]
+    pub datagrams: Vec<DatagramConfig>,
+    /// colors of the fields of colored datagrams
+    #[serde(default)]
+    pub palette: BTreeMap<FieldKind, PaletteEntry>,
+    /// id of the element that gets the legend of the palette
+    pub legend: Option<String>,
+}
+
+fn default_template() -> PathBuf {
+    PathBuf::from("./template.svg")
+}
+
+fn default_output() -> PathBuf {
+    PathBuf::from("./plakat.svg")
+}
+
+fn default_images() -> ImageMode {
+    ImageMode::Link
+}
+
+fn default_cache_dir() -> PathBuf {
+    PathBuf::from("./cache")
+}
+
+fn default_raw_data() -> PathBuf {
+    PathBuf::from("./raw_data")
+}
+
+/// PDF files rendered from the output, none by default.
+#[derive(Debug, Default, Deserialize)]
+pub struct PdfConfig {
+    pub with_text: Option<PathBuf>,
+    /// text converted to paths, so the fonts are not needed to print it
+    pub text_as_paths: Option<PathBuf>,
+}
+
+/// Time range and resolution shared by all histograms.
+#[derive(Debug, Deserialize)]
+pub struct Timeline {
+    /// ISO 8601 with offset, e.g. "2019-01-14T00:00:00+01:00", date and time without offset in
+    /// `time_zone`, e.g. "2019-01-14T08:00", or unix time in seconds with optional fraction, e.g.
+    /// "1548025199.999999999", or "auto" for the first packet of the captures of the devices
+    pub start: String,
+    /// the last moment that is still counted, a date alone means the end of that day, "auto" for
+    /// the last packet of the captures
+    pub end: String,
+    /// widen the timeline to whole days or weeks, "day" or "week"
+    pub round: Option<Rounding>,
+    /// IANA name, e.g. "Europe/Berlin", times without offset are in it, and the days on the
+    /// time axes begin at its midnight, with summer time
+    #[serde(default = "default_time_zone")]
+    pub time_zone: String,
+    /// number of buckets
+    pub width: Option<usize>,
+    /// length of a bucket instead of their number, e.g. "60s", "15m", "1h" or "1d", the end is
+    /// moved so that the last bucket is complete
+    pub bucket: Option<String>,
+}
+
+/// Value of `Timeline::start` and `end` that is taken from the captures.
+const AUTO: &str = "auto";
+
+fn default_time_zone() -> String {
+    "UTC".to_string()
+}
+
+impl Timeline {
+    pub fn time_zone(&self) -> Result<Tz> {
+        self.time_zone
+            .parse()
+            .map_err(|_| Error::Config(format!("Unknown time zone \"{}\"", self.time_zone)))
+    }
+
+    /// Whether the captures have to be scanned to know the timeline.
+    fn is_auto(&self) -> bool {
+        self.start == AUTO || self.end == AUTO
+    }
+
+    /// Time zone, start, end (included) and number of buckets. `span` of the captures is needed
+    /// for "auto".
+    fn window(&self, span: Option<&Span>) -> Result<(Tz, SystemTime, SystemTime, usize)> {
+        let tz = self.time_zone()?;
+        let auto = |t: &str| match span {
+            Some(span) => Ok(if t == "start" {
+                span.first.time
+            } else {
+                span.last.time
+            }),
+            None => Err(Error::Config(format!(
+                "The {} of the timeline can't be found, the captures have no packets",
+                t
+            ))),
+        };
+        let start = match self.start.as_str() {
+            AUTO => auto("start")?,
+            s => parse_time(s, tz, false)?,
+        };
+        let end = match self.end.as_str() {
+            AUTO => auto("end")?,
+            s => parse_time(s, tz, true)?,
+        };
+        let (start, end) = match self.round {
+            Some(round) => round.widen(start, end, tz),
+            None => (start, end),
+        };
+        let span = match end.duration_since(start) {
+            Ok(d) => d + Duration::from_nanos(1),
+            Err(_) => {
+                return Err(Error::Config(format!(
+                    "The timeline ends at {} before it starts at {}",
+                    self.end, self.start
+                )))
+            }
+        };
+        match (self.width, &self.bucket) {
+            (Some(0), None) => Err(Error::Config("The timeline needs buckets".to_string())),
+            (Some(width), None) => Ok((tz, start, end, width)),
+            (None, Some(bucket)) => {
+                let length = parse_duration(bucket)?.as_nanos();
+                let width = span.as_nanos().div_ceil(length);
+                // in nanoseconds, like in `get_bucket`, so that nothing is truncated
+                let nanos = width * length;
+                let too_long = || {
+                    Error::Config(format!(
+                        "The timeline with buckets of {} is too long",
+                        bucket
+                    ))
+                };
+                let offset = u64::try_from(nanos / 1_000_000_000)
+                    .ok()
+                    .map(|secs| Duration::new(secs, (nanos % 1_000_000_000) as u32));
+                let end = offset
+                    .and_then(|offset| start.checked_add(offset))
+                    .ok_or_else(too_long)?
+                    - Duration::from_nanos(1);
+                let width = usize::try_from(width).map_err(|_| too_long())?;
+                println!(
+                    "The timeline has {} buckets of {} and ends at {}",
+                    width,
+                    bucket,
+                    DateTime::<chrono::Utc>::from(end).with_timezone(&tz)
+                );
+                Ok((tz, start, end, width))
+            }
+            _ => Err(Error::Config(
+                "The timeline needs either `width` or `bucket`".to_string(),
+            )),
+        }
+    }
+}
+
+/// A device whose captures become one histogram per entry in `histograms`.
+#[derive(Debug, Deserialize)]
+pub struct Device {
+    pub name: String,
+    /// read as one, in the order of the timestamps, so they may overlap
+    #[serde(default)]
+    pub data_files: Vec<PathBuf>,
+    /// count packets only once that are in several of the data files
+    #[serde(default = "default_true")]
+    pub dedup: bool,
+    /// how far apart the timestamps of the same packet in two data files may be, e.g. "1s" or
+    /// "200ms", one second by default
+    pub dedup_window: Option<String>,
+    /// if a data file has no packets for longer than this, e.g. "15m", the capture wasn't
+    /// running, e.g. because the device was asleep, "none" if the data files have no gaps
+    #[serde(default = "default_max_silence")]
+    pub max_silence: String,
+}
+
+fn default_max_silence() -> String {
+    "15m".to_string()
+}
+
+impl Device {
+    fn captures(&self) -> Result<CaptureSet> {
+        let window = match self.dedup_window {
+            Some(ref w) => parse_duration(w)?,
+            None => Duration::from_secs(1),
+        };
+        Ok(CaptureSet {
+            device: self.name.clone(),
+            files: self.data_files.clone(),
+            dedup_window: if self.dedup { Some(window) } else { None },
+            max_silence: match self.max_silence.as_str() {
+                "none" => None,
+                s => Some(parse_duration(s)?),
+            },
+        })
+    }
+}
+
+/// Fills the elements with id `histo_<name>_<device>`.
+#[derive(Debug, Deserialize)]
+pub struct HistogramConfig {
+    pub name: String,
+    /// hex RGB, e.g. "ff0000"
+    #[serde(default = "default_color")]
+    pub color: String,
+    /// display filter, see `filter.rs`
+    #[serde(default)]
+    pub filter: String,
+    /// "linear", "log" or "sqrt", defaults to "log", or "linear" with `log = false`
+    pub scale: Option<Scale>,
+    #[serde(default = "default_true")]
+    pub log: bool,
+    /// pixels per unit of the scale, by default the histogram fills the height of its element
+    pub yscale: Option<f64>,
+    /// histograms with the same group share one scale, so their bars can be compared, e.g. the
+    /// same histogram of different devices
+    pub scale_group: Option<String>,
+    /// what the bars show, "bits" by default, see `Metric`
+    #[serde(default)]
+    pub metric: Metric,
+    /// draw a time axis below and a value axis left of the element
+    #[serde(default)]
+    pub axes: bool,
+    /// mark the time in which no capture of the device was running with `gap_color`
+    #[serde(default = "default_true")]
+    pub gaps: bool,
+    /// hex RGB, e.g. "e0e0e0"
+    #[serde(default = "default_gap_color")]
+    pub gap_color: String,
+    /// short for `metric = "packets"`
+    #[serde(default)]
+    pub pps: bool,
+}
+
+fn default_color() -> String {
+    "000000".to_string()
+}
+
+/// Fills the elements with id `stack_<name>_<device>` with the layers stacked in one histogram.
+#[derive(Debug, Deserialize)]
+pub struct StackedConfig {
+    pub name: String,
+    /// from bottom to top, a packet that matches several layers counts for the lowest of them
+    pub layers: Vec<LayerConfig>,
+    /// display filter all layers have to match, e.g. "ip", all packets by default
+    #[serde(default)]
+    pub filter: String,
+    /// one more layer on top for the packets that match none of the layers
+    #[serde(default = "default_true")]
+    pub other: bool,
+    #[serde(default = "default_other_label")]
+    pub other_label: String,
+    /// hex RGB, e.g. "cccccc"
+    #[serde(default = "default_other_color")]
+    pub other_color: String,
+    /// like in `HistogramConfig`, the scale is fitted to the sum of all layers
+    pub scale: Option<Scale>,
+    pub yscale: Option<f64>,
+    pub scale_group: Option<String>,
+    /// "bits", "bytes", "packets" or "flows", the others don't add up
+    #[serde(default)]
+    pub metric: Metric,
+    #[serde(default)]
+    pub axes: bool,
+    #[serde(default = "default_true")]
+    pub gaps: bool,
+    #[serde(default = "default_gap_color")]
+    pub gap_color: String,
+    /// id of the element that gets the legend of the layers
+    pub legend: Option<String>,
+}
+
+/// One protocol of a stacked histogram, e.g.
+/// `{ label = "DNS", filter = "udp.port==53 || tcp.port==53", color = "ff7f0e" }`.
+#[derive(Debug, Deserialize)]
+pub struct LayerConfig {
+    pub label: String,
+    /// display filter, see `filter.rs`
+    pub filter: String,
+    /// hex RGB, e.g. "ff0000"
+    pub color: String,
+}
+
+fn default_other_label() -> String {
+    "other".to_string()
+}
+
+fn default_other_color() -> String {
+    "cccccc".to_string()
+}
+
+fn default_gap_color() -> String {
+    "e0e0e0".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_spacing() -> usize {
+    1
+}
+
+/// Fills the element with id `datag_<name>`, or with `split` the elements `datag_<name>-01`,
+/// `datag_<name>-02`, ... with one packet each.
+#[derive(Debug, Deserialize)]
+pub struct DatagramConfig {
+    pub name: String,
+    #[serde(default)]
+    pub gray_value: u8,
+    /// defaults to `<raw_data>/<name>.pcapng`, or a compressed version of it, see `capture.rs`
+    pub path: Option<PathBuf>,
+    /// frame numbers of the drawn packets, e.g. "5", "1-18" or "3-", by default the first packet,
+    /// or all packets if there is a `filter`
+    pub packets: Option<String>,
+    /// display filter the drawn packets have to match, see `filter.rs`
+    #[serde(default)]
+    pub filter: String,
+    /// one element per packet instead of all packets stacked in one element
+    #[serde(default)]
+    pub split: bool,
+    /// empty lines between the stacked packets
+    #[serde(default = "default_spacing")]
+    pub spacing: usize,
+    /// color the bits with the palette, by the header field they belong to
+    #[serde(default)]
+    pub colored: bool,
+    /// draw the "first" or "last" packet of the captures of the devices, instead of a capture
+    /// of its own
+    pub extract: Option<Extract>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Extract {
+    First,
+    Last,
+}
+
+impl fmt::Display for Extract {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Extract::First => write!(f, "first"),
+            Extract::Last => write!(f, "last"),
+        }
+    }
+}
+
+/// Color of one kind of field, e.g. `ip_address = { color = "ff0000", label = "IP-Adresse" }`.
+#[derive(Debug, Deserialize)]
+pub struct PaletteEntry {
+    /// hex RGB, e.g. "ff0000"
+    pub color: String,
+    /// text in the legend, defaults to an english description of the field
+    pub label: Option<String>,
+}
+
+impl PosterConfig {
+    pub fn from_file(path: &Path) -> Result<PosterConfig> {
+        let s = std::fs::read_to_string(path).map_err(Error::io(path))?;
+        toml::from_str(&s)
+            .map_err(|e| Error::Config(format!("Could not parse {}: {}", path.display(), e)))
+    }
+
+    /// The poster with all its elements, that uses `cache`. If the timeline or a datagram needs
+    /// the time span of the captures, up to `scan_jobs` of them are read at the same time,
+    /// unless their spans are in the cache. With `None`, captures aren't read at all and their
+    /// spans have to be in the cache.
+    pub fn to_plakat(&self, cache: Cache, scan_jobs: Option<usize>) -> Result<Plakat> {
+        let mut p = Plakat::new(self.width, self.height);
+        p.template_path = self.template.clone();
+        p.output_path = self.output.clone();
+        p.cache = cache;
+        p.images = self.images;
+        p.vector = self.vector;
+        p.pdf_path = self.pdf.with_text.clone();
+        p.pdf_outlined_path = self.pdf.text_as_paths.clone();
+        let span = if self.timeline.is_auto() || self.datagrams.iter().any(|d| d.extract.is_some())
+        {
+            p.scanned = self.captures();
+            let spans = match scan_jobs {
+                // a capture that can't be read fails the histograms that use it, when they are
+                // drawn, and is left out here
+                Some(jobs) => scan::scan_all(&p.cache, &p.scanned, jobs)
+                    .into_iter()
+                    .filter_map(|s| s.map_err(|e| println!("{}", e)).ok())
+                    .collect(),
+                None => {
+                    let mut spans = Vec::new();
+                    for c in &p.scanned {
+                        spans.push(scan::cached(&p.cache, c).ok_or_else(|| {
+                            Error::Config(format!(
+                                "{} wasn't scanned yet, render the poster or run `plakat scan` \
+                                 first",
+                                c.display()
+                            ))
+                        })?);
+                    }
+                    spans
+                }
+            };
+            Span::all(spans.into_iter().flatten())
+        } else {
+            None
+        };
+        let (time_zone, start_time, end_time, width) = self.timeline.window(span.as_ref())?;
+        let mut palette = Palette::new();
+        for (kind, entry) in &self.palette {
+            palette.insert(*kind, parse_color(&entry.color)?);
+        }
+        let datagram_palette = |d: &DatagramConfig| {
+            if d.colored {
+                Some(palette.clone())
+            } else {
+                None
+            }
+        };
+        for d in &self.datagrams {
+            if let Some(extract) = d.extract {
+                let span = match span {
+                    Some(ref span) => span,
+                    None => {
+                        let message = format!(
+                            "There is no {} packet for datagram {}, the captures have no packets",
+                            extract, d.name
+                        );
+                        p.elements
+                            .insert(format!("datag_{}", d.name), Box::new(Unavailable(message)));
+                        continue;
+                    }
+                };
+                let found = match extract {
+                    Extract::First => &span.first,
+                    Extract::Last => &span.last,
+                };
+                let datagram = Datagram {
+                    packet_path: found.path.clone(),
+                    packets: PacketSelection::frame(found.frame),
+                    spacing: d.spacing,
+                    gray_value: d.gray_value,
+                    palette: datagram_palette(d),
+                };
+                p.elements
+                    .insert(format!("datag_{}", d.name), Box::new(datagram));
+                continue;
+            }
+            let packet_path = d
+                .path
+                .clone()
+                .unwrap_or_else(|| default_capture(&self.raw_data, &d.name));
+            Filter::parse(&d.filter)?;
+            let default_range = if d.filter.is_empty() { "1" } else { "1-" };
+            let packets = PacketSelection::parse_range(
+                d.packets.as_deref().unwrap_or(default_range),
+                &d.filter,
+            )?;
+            let datagram = |packets: PacketSelection| Datagram {
+                packet_path: packet_path.clone(),
+                packets,
+                spacing: d.spacing,
+                gray_value: d.gray_value,
+                palette: datagram_palette(d),
+            };
+            if !d.split {
+                p.elements
+                    .insert(format!("datag_{}", d.name), Box::new(datagram(packets)));
+                continue;
+            }
+            // the number of elements depends on the capture
+            let frames = match packets.frames(&packet_path) {
+                Ok(frames) => frames,
+                Err(e) => {
+                    // only the first element is known to exist, it reports the error
+                    p.elements.insert(
+                        format!("datag_{}-01", d.name),
+                        Box::new(Unavailable(e.to_string())),
+                    );
+                    continue;
+                }
+            };
+            for (i, &frame) in frames.iter().enumerate() {
+                p.elements.insert(
+                    format!("datag_{}-{:02}", d.name, i + 1),
+                    Box::new(datagram(PacketSelection::frame(frame))),
+                );
+            }
+        }
+        if let Some(ref id) = self.legend {
+            let legend = Legend {
+                entries: self
+                    .palette
+                    .iter()
+                    .map(|(kind, entry)| {
+                        let label = entry.label.as_deref().unwrap_or_else(|| kind.label());
+                        (label.to_string(), palette[kind])
+                    })
+                    .collect(),
+            };
+            p.elements.insert(id.clone(), Box::new(legend));
+        }
+        let mut histograms: Vec<(String, Option<&String>, Histogram)> = Vec::new();
+        for h in &self.histograms {
+            let color = parse_color(&h.color)?;
+            let metric = if h.pps { Metric::Packets } else { h.metric };
+            let scale = h
+                .scale
+                .unwrap_or(if h.log { Scale::Log } else { Scale::Linear });
+            for device in &self.devices {
+                let histo = Histogram {
+                    scale,
+                    yscale: h.yscale,
+                    height: DEFAULT_HEIGHT,
+                    color,
+                    gap_color: if h.gaps {
+                        Some(parse_color(&h.gap_color)?)
+                    } else {
+                        None
+                    },
+                    data: HistogramData {
+                        filter: Some(h.filter.clone()),
+                        filter_description: Some(h.name.clone()),
+                        start_time,
+                        end_time,
+                        width,
+                        metric,
+                        captures: device.captures()?,
+                    },
+                    peers: Vec::new(),
+                    axes: h.axes,
+                    time_zone,
+                    fitted: Mutex::new(None),
+                };
+                let id = format!("histo_{}_{}", h.name, device.name);
+                histograms.push((id, h.scale_group.as_ref(), histo));
+            }
+        }
+        share_scale_groups(
+            &mut histograms,
+            |h| h.data.clone(),
+            |h, peers| h.peers = peers,
+        );
+        for (id, _, histo) in histograms {
+            p.elements.insert(id, Box::new(histo));
+        }
+        let mut stacks: Vec<(String, Option<&String>, StackedHistogram)> = Vec::new();
+        for s in &self.stacked_histograms {
+            if !s.metric.is_additive() {
+                return Err(Error::Config(format!(
+                    "The layers of {} can't be stacked, {} don't add up",
+                    s.name, s.metric
+                )));
+            }
+            // label and color of every layer, for the legend
+            let mut labels: Vec<(String, (u8, u8, u8))> = Vec::new();
+            // filter and name in the cache of every layer
+            let mut filters: Vec<(String, String)> = Vec::new();
+            for (i, l) in s.layers.iter().enumerate() {
+                if l.filter.is_empty() {
+                    return Err(Error::Config(format!(
+                        "Layer {} of {} needs a filter",
+                        l.label, s.name
+                    )));
+                }
+                let below: Vec<&str> = s.layers[..i].iter().map(|l| l.filter.as_str()).collect();
+                let filter = exclusive_filter(&[&s.filter, &l.filter], &below);
+                labels.push((l.label.clone(), parse_color(&l.color)?));
+                filters.push((filter, format!("{}-{}", s.name, i + 1)));
+            }
+            if s.other {
+                let all: Vec<&str> = s.layers.iter().map(|l| l.filter.as_str()).collect();
+                labels.push((s.other_label.clone(), parse_color(&s.other_color)?));
+                filters.push((
+                    exclusive_filter(&[&s.filter], &all),
+                    format!("{}-other", s.name),
+                ));
+            }
+            for (filter, _) in &filters {
+                Filter::parse(filter)?;
+            }
+            if let Some(ref id) = s.legend {
+                // top to bottom, like the layers
+                let legend = Legend {
+                    entries: labels.iter().rev().cloned().collect(),
+                };
+                p.elements.insert(id.clone(), Box::new(legend));
+            }
+            let scale = s.scale.unwrap_or(Scale::Log);
+            for device in &self.devices {
+                let captures = device.captures()?;
+                let stack = StackedHistogram {
+                    scale,
+                    yscale: s.yscale,
+                    height: DEFAULT_HEIGHT,
+                    layers: labels
+                        .iter()
+                        .zip(&filters)
+                        .map(|(&(_, color), (filter, name))| Layer {
+                            color,
+                            data: HistogramData {
+                                filter: Some(filter.clone()),
+                                filter_description: Some(name.clone()),
+                                start_time,
+                                end_time,
+                                width,
+                                metric: s.metric,
+                                captures: captures.clone(),
+                            },
+                        })
+                        .collect(),
+                    peers: Vec::new(),
+                    gap_color: if s.gaps {
+                        Some(parse_color(&s.gap_color)?)
+                    } else {
+                        None
+                    },
+                    axes: s.axes,
+                    time_zone,
+                    stacking: Mutex::new(None),
+                };
+                let id = format!("stack_{}_{}", s.name, device.name);
+                stacks.push((id, s.scale_group.as_ref(), stack));
+            }
+        }
+        share_scale_groups(
+            &mut stacks,
+            |s| s.layers.iter().map(|l| l.data.clone()).collect(),
+            |s, peers| s.peers = peers,
+        );
+        for (id, _, stack) in stacks {
+            p.elements.insert(id, Box::new(stack));
+        }
+        p.fit_to_template()?;
+        Ok(p)
+    }
+
+    /// The captures of all devices, each one once.
+    pub fn captures(&self) -> Vec<PathBuf> {
+        let mut res: Vec<PathBuf> = Vec::new();
+        for f in self.devices.iter().flat_map(|d| &d.data_files) {
+            if !res.contains(f) {
+                res.push(f.clone());
+            }
+        }
+        res
+    }
+}
+
+/// `<raw_data>/<name>.pcapng`, or `<raw_data>/<name>.pcapng.xz` etc. if only that exists.
+fn default_capture(raw_data: &Path, name: &str) -> PathBuf {
+    let plain = raw_data.join(format!("{}.pcapng", name));
+    if plain.exists() {
+        return plain;
+    }
+    COMPRESSED_EXTENSIONS
+        .iter()
+        .map(|ext| raw_data.join(format!("{}.pcapng.{}", name, ext)))
+        .find(|p| p.exists())
+        .unwrap_or(plain)
+}
+
+/// A point in time, see `Timeline::start`. With `end_of_day`, a date alone means the last moment
+/// of the day instead of its beginning.
+fn parse_time(s: &str, tz: Tz, end_of_day: bool) -> Result<SystemTime> {
+    if s.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return parse_unix_time(s);
+    }
+    let err = || Error::Config(format!("Invalid time \"{}\"", s));
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(SystemTime::from(t));
+    }
+    if let Ok(t) = DateTime::parse_from_str(s, "%Y-%m-%dT%H:%M%:z") {
+        return Ok(SystemTime::from(t));
+    }
+    let local = |t: NaiveDateTime| {
+        tz.from_local_datetime(&t)
+            .earliest()
+            .map(SystemTime::from)
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "There is no {} in {}, because of summer time",
+                    s, tz
+                ))
+            })
+    };
+    for format in &[
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M:%S%.f",
+    ] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return local(t);
+        }
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| err())?;
+    if !end_of_day {
+        return local(date.and_hms_opt(0, 0, 0).unwrap());
+    }
+    let next = local(
+        date.succ_opt()
+            .ok_or_else(err)?
+            .and_hms_opt(0, 0, 0)
+            .unwrap(),
+    )?;
+    Ok(next - Duration::from_nanos(1))
+}
+
+/// A length of time like "500ms", "90s", "15m", "1h" or "1d".
+/// Give every element with a scale group the data of the other elements in the same group, that
+/// it needs to find a common scale. The elements are given by id and scale group.
+fn share_scale_groups<T, P: Clone>(
+    elements: &mut [(String, Option<&String>, T)],
+    data: impl Fn(&T) -> P,
+    set_peers: impl Fn(&mut T, Vec<P>),
+) {
+    let mut groups: BTreeMap<&String, Vec<usize>> = BTreeMap::new();
+    for (i, &(_, group, _)) in elements.iter().enumerate() {
+        if let Some(group) = group {
+            groups.entry(group).or_default().push(i);
+        }
+    }
+    for members in groups.values() {
+        let data: Vec<P> = members.iter().map(|&i| data(&elements[i].2)).collect();
+        for (k, &i) in members.iter().enumerate() {
+            let peers = data
+                .iter()
+                .enumerate()
+                .filter(|&(l, _)| l != k)
+                .map(|(_, d)| d.clone())
+                .collect();
+            set_peers(&mut elements[i].2, peers);
+        }
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration> {
+    let err = || Error::Config(format!("Invalid duration \"{}\", expected e.g. \"60s\"", s));
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let number: u64 = number.parse().map_err(|_| err())?;
+    let millis = match unit.trim() {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" | "min" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(err()),
+    };
+    if number == 0 {
+        return Err(err());
+    }
+    let millis = number
+        .checked_mul(millis)
+        .ok_or_else(|| Error::Config(format!("The duration \"{}\" is too long", s)))?;
+    Ok(Duration::from_millis(millis))
+}
+
+fn parse_unix_time(s: &str) -> Result<SystemTime> {
+    let err = || Error::Config(format!("Invalid unix time \"{}\"", s));
+    let mut split = s.splitn(2, '.');
+    let secs: u64 = split.next().unwrap_or("").parse().map_err(|_| err())?;
+    let nanos: u32 = match split.next() {
+        Some(f) if !f.is_empty() && f.len() <= 9 => {
+            // pad the fraction to nanoseconds
+            format!("{:0<9}", f).parse().map_err(|_| err())?
+        }
+        Some(_) => return Err(err()),
+        None => 0,
+    };
+    Ok(SystemTime::UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+fn parse_color(s: &str) -> Result<(u8, u8, u8)> {
+    let err = || Error::Config(format!("Invalid color \"{}\", expected six hex digits", s));
+    if s.len() != 6 || !s.is_ascii() {
+        return Err(err());
+    }
+    let c = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| err());
+    Ok((c(0)?, c(2)?, c(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn berlin() -> Tz {
+        "Europe/Berlin".parse().unwrap()
+    }
+
+    fn timeline(start: &str, end: &str, width: Option<usize>, bucket: Option<&str>) -> Timeline {
+        Timeline {
+            start: start.to_string(),
+            end: end.to_string(),
+            round: None,
+            time_zone: "Europe/Berlin".to_string(),
+            width,
+            bucket: bucket.map(|b| b.to_string()),
+        }
+    }
+
+    const NANO: Duration = Duration::from_nanos(1);
+    // 2019-01-14T00:00:00+01:00
+    const MONDAY: u64 = 1_547_420_400;
+
+    #[test]
+    fn times_with_offset() {
+        let tz = berlin();
+        let t = parse_time("2019-01-14T00:00:00+01:00", tz, false).unwrap();
+        assert_eq!(t, unix(MONDAY));
+        let t = parse_time("2019-01-13T23:00+00:00", tz, false).unwrap();
+        assert_eq!(t, unix(MONDAY));
+        // the offset wins over the time zone, also for the end
+        let t = parse_time("2019-01-14T00:00:00+01:00", tz, true).unwrap();
+        assert_eq!(t, unix(MONDAY));
+    }
+
+    #[test]
+    fn times_in_time_zone() {
+        let tz = berlin();
+        assert_eq!(
+            parse_time("2019-01-14T08:00", tz, false).unwrap(),
+            unix(MONDAY + 8 * 3600)
+        );
+        assert_eq!(
+            parse_time("2019-01-14 08:00:00.5", tz, false).unwrap(),
+            unix(MONDAY + 8 * 3600) + Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn date_alone() {
+        let tz = berlin();
+        assert_eq!(parse_time("2019-01-14", tz, false).unwrap(), unix(MONDAY));
+        // the last nanosecond of the day
+        assert_eq!(
+            parse_time("2019-01-14", tz, true).unwrap(),
+            unix(MONDAY + 24 * 3600) - NANO
+        );
+        // summer time starts, the day has 23 hours
+        assert_eq!(
+            parse_time("2019-03-31", tz, true).unwrap(),
+            parse_time("2019-04-01T00:00:00+02:00", tz, false).unwrap() - NANO
+        );
+    }
+
+    #[test]
+    fn summer_time() {
+        let tz = berlin();
+        // skipped when the clocks are put forward
+        assert!(parse_time("2019-03-31T02:30", tz, false).is_err());
+        // twice when they are put back, the first one counts
+        assert_eq!(
+            parse_time("2019-10-27T02:30", tz, false).unwrap(),
+            parse_time("2019-10-27T02:30:00+02:00", tz, false).unwrap()
+        );
+    }
+
+    #[test]
+    fn unix_times() {
+        assert_eq!(parse_unix_time("1547420400").unwrap(), unix(1_547_420_400));
+        assert_eq!(
+            parse_unix_time("1548025199.999999999").unwrap(),
+            unix(1_548_025_200) - NANO
+        );
+        assert_eq!(
+            parse_unix_time("1.5").unwrap(),
+            unix(1) + Duration::from_millis(500)
+        );
+        assert!(parse_unix_time("1.").is_err());
+        assert!(parse_unix_time("1.0000000001").is_err());
+        assert!(parse_unix_time("").is_err());
+        assert!(parse_unix_time("yesterday").is_err());
+        // digits are taken as unix time
+        assert_eq!(
+            parse_time("1547420400", berlin(), false).unwrap(),
+            unix(MONDAY)
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("60").unwrap(), Duration::from_secs(60));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("15min").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration(" 1h ").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("").is_err());
+        // more milliseconds than fit into 64 bits
+        assert!(parse_duration("18446744073709552s").is_err());
+        assert!(parse_duration("300000000000d").is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn window_with_width() {
+        let (_, start, end, width) = timeline("2019-01-14", "2019-01-20", Some(7), None)
+            .window(None)
+            .unwrap();
+        assert_eq!(start, unix(MONDAY));
+        assert_eq!(end, unix(MONDAY + 7 * 24 * 3600) - NANO);
+        assert_eq!(width, 7);
+    }
+
+    #[test]
+    fn window_with_bucket() {
+        // exactly 24 buckets, the end stays
+        let (_, start, end, width) = timeline("2019-01-14", "2019-01-14", None, Some("1h"))
+            .window(None)
+            .unwrap();
+        assert_eq!(start, unix(MONDAY));
+        assert_eq!(end, unix(MONDAY + 24 * 3600) - NANO);
+        assert_eq!(width, 24);
+        // the last bucket is completed
+        let (_, _, end, width) = timeline("2019-01-14", "2019-01-14", None, Some("7h"))
+            .window(None)
+            .unwrap();
+        assert_eq!(width, 4);
+        assert_eq!(end, unix(MONDAY + 28 * 3600) - NANO);
+        // one nanosecond more needs another bucket
+        let (_, _, _, width) = timeline("2019-01-14", "1547424000.000000001", None, Some("1h"))
+            .window(None)
+            .unwrap();
+        assert_eq!(width, 2);
+        // more buckets than fit into 32 bits
+        let (_, _, end, width) = timeline("2019-01-14", "2019-03-24", None, Some("1ms"))
+            .window(None)
+            .unwrap();
+        assert_eq!(width, 70 * 24 * 3600 * 1000);
+        assert_eq!(end, unix(MONDAY + 70 * 24 * 3600) - NANO);
+    }
+
+    #[test]
+    fn invalid_windows() {
+        let window = |start, end, width, bucket| timeline(start, end, width, bucket).window(None);
+        assert!(window("2019-01-14", "2019-01-20", None, None).is_err());
+        assert!(window("2019-01-14", "2019-01-20", Some(7), Some("1d")).is_err());
+        assert!(window("2019-01-14", "2019-01-20", Some(0), None).is_err());
+        assert!(window("2019-01-20", "2019-01-14", Some(7), None).is_err());
+        // the captures are needed for "auto"
+        assert!(window("auto", "2019-01-20", Some(7), None).is_err());
+        // a single nanosecond is fine
+        assert!(window("2019-01-14T00:00:00+01:00", "1547420400", Some(1), None).is_ok());
+    }
+
+    #[test]
+    fn scale_groups() {
+        let (a, b) = ("a".to_string(), "b".to_string());
+        // the data of an element, and the data of its peers
+        type Element = (u32, Vec<u32>);
+        let mut elements: Vec<(String, Option<&String>, Element)> = vec![
+            ("1".to_string(), Some(&a), (1, Vec::new())),
+            ("2".to_string(), None, (2, Vec::new())),
+            ("3".to_string(), Some(&b), (3, Vec::new())),
+            ("4".to_string(), Some(&a), (4, Vec::new())),
+            ("5".to_string(), Some(&a), (5, Vec::new())),
+        ];
+        share_scale_groups(&mut elements, |e| e.0, |e, peers| e.1 = peers);
+        let peers: Vec<&[u32]> = elements.iter().map(|e| &(e.2).1[..]).collect();
+        assert_eq!(peers, vec![&[4, 5][..], &[], &[], &[1, 5], &[1, 4]]);
+    }
+}
@@ -2,108 +2,400 @@ use std::fs::File;
 
 // For reading and opening files
 use std::io::BufWriter;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 // To use encoder.set()
 use png::HasParameters;
 
+use std::collections::BTreeMap;
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use axes;
+use cachable::{CachablePNG, Cache, CacheKey};
+use capture;
+use error::{Error, Result};
+use filter::Filter;
+use packet::{dissect, field_kinds, FieldKind};
 
-use cachable::CachablePNG;
+use pcarp::LinkType;
 
-use pcarp::Capture;
-use std::time::UNIX_EPOCH;
+use svg::node::element::{Group, Rectangle, Text};
+use svg::node::Node;
+
+type Rgb = (u8, u8, u8);
+
+/// Color of the bits of each kind of field.
+pub type Palette = BTreeMap<FieldKind, Rgb>;
+
+/// Frame number, link type and bytes of a packet.
+pub type FramePacket = (usize, Option<LinkType>, Vec<u8>);
+
+/// Which packets of a capture are drawn: those with a frame number (starting at 1, like in
+/// Wireshark) from `first` to `last`, both included, that match `filter`.
+#[derive(Debug, Clone)]
+pub struct PacketSelection {
+    pub first: usize,
+    /// up to the end of the capture if `None`
+    pub last: Option<usize>,
+    pub filter: String,
+}
+
+impl PacketSelection {
+    /// Only the packet with frame number `n`.
+    pub fn frame(n: usize) -> PacketSelection {
+        PacketSelection {
+            first: n,
+            last: Some(n),
+            filter: String::new(),
+        }
+    }
+
+    /// Parse a range of frame numbers like `5`, `1-18` or `3-`.
+    pub fn parse_range(range: &str, filter: &str) -> Result<PacketSelection> {
+        let err = || Error::Config(format!("Invalid packet range \"{}\"", range));
+        let number = |s: &str| {
+            s.trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(err)
+        };
+        let (first, last) = match range.find('-') {
+            Some(i) if range[i + 1..].trim().is_empty() => (number(&range[..i])?, None),
+            Some(i) => (number(&range[..i])?, Some(number(&range[i + 1..])?)),
+            None => (number(range)?, Some(number(range)?)),
+        };
+        if last.is_some_and(|last| last < first) {
+            return Err(err());
+        }
+        Ok(PacketSelection {
+            first,
+            last,
+            filter: filter.to_string(),
+        })
+    }
+
+    /// Frame numbers, link types and bytes of the selected packets of the capture at `path`.
+    pub fn packets(&self, path: &Path) -> Result<Vec<FramePacket>> {
+        let mut res = Vec::new();
+        self.for_each(path, |frame, link_type, data| {
+            res.push((frame, link_type, data.to_vec()))
+        })?;
+        Ok(res)
+    }
+
+    /// Only the frame numbers of the selected packets, without keeping their bytes.
+    pub fn frames(&self, path: &Path) -> Result<Vec<usize>> {
+        let mut res = Vec::new();
+        self.for_each(path, |frame, _, _| res.push(frame))?;
+        Ok(res)
+    }
+
+    /// Call `f` with the frame number, link type and bytes of every selected packet.
+    fn for_each<F>(&self, path: &Path, mut f: F) -> Result<()>
+    where
+        F: FnMut(usize, Option<LinkType>, &[u8]),
+    {
+        let filter = Filter::parse(&self.filter)?;
+        let mut pcap = capture::open(path)?;
+        let mut frame = 0;
+        while let Some(pkt) = pcap.next() {
+            let pkt = pkt.map_err(capture::capture_error(path))?;
+            frame += 1;
+            if frame < self.first {
+                continue;
+            }
+            if self.last.is_some_and(|last| frame > last) {
+                break;
+            }
+            let link_type = pkt.interface.map(|i| i.link_type);
+            if filter.matches(&dissect(link_type, pkt.data)) {
+                f(frame, link_type, pkt.data);
+            }
+        }
+        Ok(())
+    }
+}
 
-#[derive(Hash)]
+/// One or more packets of a capture, drawn bit by bit. Every packet starts at a new line, with
+/// `spacing` empty lines between two packets.
 pub struct Datagram {
     pub packet_path: PathBuf,
+    pub packets: PacketSelection,
+    pub spacing: usize,
     pub gray_value: u8,
+    /// Color the bits by the field they belong to. Fields without a color in the palette are
+    /// drawn with `gray_value`.
+    pub palette: Option<Palette>,
 }
 
 impl CachablePNG for Datagram {
-    fn png_cached(&self) -> Result<PathBuf, &str> {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        let hash: u64 = hasher.finish();
-        let path = PathBuf::from(&format!("./cache/{}.png", &hash.to_string()));
-        if path.exists() {
-            println!("Cache hit for {}", &path.to_str().unwrap());
-            Ok(path)
-        } else {
-            println!("Cache miss for {}", &path.to_str().unwrap());
-            self.png_uncached(path)
+    fn cache_key(&self) -> Option<CacheKey> {
+        let mut key = CacheKey::new("datagram 3");
+        key.add_input("packet_path", &self.packet_path);
+        key.add("first", &self.packets.first);
+        key.add("last", &format!("{:?}", self.packets.last));
+        key.add("filter", &self.packets.filter);
+        key.add("spacing", &self.spacing);
+        key.add("gray_value", &self.gray_value);
+        if let Some(ref palette) = self.palette {
+            for (kind, color) in palette {
+                key.add(&format!("{:?}", kind), &format!("{:?}", color));
+            }
         }
+        Some(key)
     }
-    fn png_uncached(&self, path: PathBuf) -> Result<PathBuf, &str> {
-        let file = match File::open(&self.packet_path) {
-            Ok(f) => f,
-            Err(_) => {
-                panic!("Could not find {}", &self.packet_path.to_str().unwrap());
-            },
-        };
-        let mut pcap = Capture::new(file).unwrap();
-        let pkt = pcap.next().unwrap().unwrap();
-        let _ts = pkt.timestamp.unwrap_or(UNIX_EPOCH);
-
-        let packet_size: usize = pkt.data.len();
-
-        let lines: usize = packet_size / (32) + 1;
-        let pixel: usize = lines * 32 * 8;
+    fn png_uncached(&self, _cache: &Cache, path: PathBuf) -> Result<PathBuf> {
+        let (data, colors) = self.layout()?;
+        let lines = data.len() / BYTES_PER_LINE;
+        let pixel: usize = lines * LINE_WIDTH;
         println!("{} lines, {} pixel", lines, pixel);
 
         // actual image data
         let mut image: Vec<u8> = vec![255; pixel * 4];
 
         // build image
-        for i in 0..pkt.data.len() {
+        for (i, &(r, g, b)) in colors.iter().enumerate() {
             for j in 0..8 {
-                if pkt.data[i] & (1 << 7 - j) != 0 {
-                    for rgb_offset in 0..3 {
-                        image[((i * 8) + j) * 4 + rgb_offset] = self.gray_value;
-                    }
+                if data[i] & (1 << (7 - j)) != 0 {
+                    let offset = ((i * 8) + j) * 4;
+                    image[offset..offset + 3].copy_from_slice(&[r, g, b]);
                 }
             }
         }
 
         println!("Datagram will have {} lines", lines);
 
-        let file = File::create(path.clone()).unwrap();
-        let ref mut w = BufWriter::new(file);
+        let file = File::create(&path).map_err(Error::io(&path))?;
+        let w = &mut BufWriter::new(file);
 
-        let mut encoder = png::Encoder::new(w, 32 * 8, lines as u32);
+        let mut encoder = png::Encoder::new(w, LINE_WIDTH as u32, lines as u32);
         // Width is 2 pixels and height is 1.
         encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&image).unwrap(); // Save
+        let png_error = |e: png::EncodingError| Error::Cache(path.clone(), e.to_string());
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        writer.write_image_data(&image).map_err(png_error)?; // Save
 
         Ok(path)
     }
+
+    fn svg(&self, _cache: &Cache) -> Result<Option<(Group, u32, u32)>> {
+        let (data, colors) = self.layout()?;
+        let lines = data.len() / BYTES_PER_LINE;
+        let g = svg_from_data(&data, &colors)
+            .set("shape-rendering", "crispEdges")
+            .set("stroke", "none");
+        Ok(Some((g, LINE_WIDTH as u32, lines as u32)))
+    }
 }
 
-/* unused code
-use svg::node::element::Group;
-use svg::node::element::Rectangle;
-use svg::node::Node;
-fn svg_from_data(data: Vec<u8>, pixel_width: f64, line_width: u64) -> Group {
-    let mut g = Group::new();
-    for i in 0..data.len() {
-        g.append(
-            Rectangle::new()
-                .set("x", (i as u64 % line_width) as f64 * pixel_width)
-                .set("y", (i as u64 / line_width) as f64 * pixel_width)
-                .set("width", pixel_width)
-                .set("height", pixel_width)
-                .set(
-                    "style",
-                    format!(
-                        "fill:rgb({0},{0},{0});stroke:rgb({0},{0},{0});stroke-width:0.0",
-                        data[i]
-                    ),
-                ),
-        );
+/// Bits per line of the image.
+const LINE_WIDTH: usize = 32 * 8;
+const BYTES_PER_LINE: usize = LINE_WIDTH / 8;
+
+/// Full lines needed for the bytes of a packet.
+fn lines(data: &[u8]) -> usize {
+    data.len().div_ceil(BYTES_PER_LINE)
+}
+
+impl Datagram {
+    /// The bytes of all selected packets, each padded with zeros to full lines and followed by
+    /// the empty spacing lines, together with the color of the set bits of each byte.
+    fn layout(&self) -> Result<(Vec<u8>, Vec<Rgb>)> {
+        let packets = self.packets.packets(&self.packet_path)?;
+        if packets.is_empty() {
+            return Err(Error::Render(format!(
+                "No packets selected in {}",
+                self.packet_path.display()
+            )));
+        }
+        let mut data = Vec::new();
+        let mut colors = Vec::new();
+        for (i, &(_, link_type, ref packet)) in packets.iter().enumerate() {
+            if i > 0 {
+                data.resize(data.len() + self.spacing * BYTES_PER_LINE, 0);
+                colors.resize(data.len(), (0, 0, 0));
+            }
+            colors.extend(self.byte_colors(link_type, packet));
+            data.extend_from_slice(packet);
+            data.resize(
+                (data.len() - packet.len()) + lines(packet) * BYTES_PER_LINE,
+                0,
+            );
+            colors.resize(data.len(), (0, 0, 0));
+        }
+        Ok((data, colors))
+    }
+
+    /// Color of the set bits of each byte of a packet.
+    fn byte_colors(&self, link_type: Option<LinkType>, data: &[u8]) -> Vec<(u8, u8, u8)> {
+        let gray = (self.gray_value, self.gray_value, self.gray_value);
+        match self.palette {
+            Some(ref palette) => field_kinds(link_type, data)
+                .into_iter()
+                .map(|k| palette.get(&k).cloned().unwrap_or(gray))
+                .collect(),
+            None => vec![gray; data.len()],
+        }
+    }
+}
+
+/// One rect per horizontal run of set bits of the same color, a bit is one unit wide and high.
+/// The rects are grouped by color.
+fn svg_from_data(data: &[u8], colors: &[(u8, u8, u8)]) -> Group {
+    let bit = |i: usize| data[i / 8] & (1 << (7 - i % 8)) != 0;
+    let color = |i: usize| colors[i / 8];
+    let bits = data.len() * 8;
+    let mut groups: BTreeMap<(u8, u8, u8), Group> = BTreeMap::new();
+    let mut i = 0;
+    while i < bits {
+        if !bit(i) {
+            i += 1;
+            continue;
+        }
+        // runs don't continue into the next line
+        let line_end = (i / LINE_WIDTH + 1) * LINE_WIDTH;
+        let start = i;
+        while i < bits && i < line_end && bit(i) && color(i) == color(start) {
+            i += 1;
+        }
+        let (r, g, b) = color(start);
+        groups
+            .entry(color(start))
+            .or_insert_with(|| Group::new().set("fill", format!("rgb({},{},{})", r, g, b)))
+            .append(
+                Rectangle::new()
+                    .set("x", start % LINE_WIDTH)
+                    .set("y", start / LINE_WIDTH)
+                    .set("width", i - start)
+                    .set("height", 1),
+            );
+    }
+    let mut res = Group::new();
+    for (_, g) in groups {
+        res.append(g);
     }
-    return g;
+    res
 }
-*/
 
+/// Explains the colors of the datagrams, one colored square and label per line. Text can't be
+/// drawn into a PNG, so there is only the vector version.
+pub struct Legend {
+    pub entries: Vec<(String, (u8, u8, u8))>,
+}
+
+/// Height of one line of the legend, in units of the legend's size.
+const LEGEND_LINE: u32 = 16;
+const LEGEND_WIDTH: u32 = 200;
+
+impl CachablePNG for Legend {
+    fn png_cached(&self, _cache: &Cache) -> Result<PathBuf> {
+        Err(Error::Render(
+            "The legend can only be drawn as vector graphics".to_string(),
+        ))
+    }
+    fn png_uncached(&self, _cache: &Cache, _path: PathBuf) -> Result<PathBuf> {
+        Err(Error::Render(
+            "The legend can only be drawn as vector graphics".to_string(),
+        ))
+    }
+
+    fn svg(&self, _cache: &Cache) -> Result<Option<(Group, u32, u32)>> {
+        let mut g = Group::new()
+            .set("font-family", "sans-serif")
+            .set("font-size", 12);
+        for (i, &(ref label, (r, gr, b))) in self.entries.iter().enumerate() {
+            let y = i as u32 * LEGEND_LINE;
+            g.append(
+                Rectangle::new()
+                    .set("x", 0)
+                    .set("y", y + 2)
+                    .set("width", 12)
+                    .set("height", 12)
+                    .set("fill", format!("rgb({},{},{})", r, gr, b)),
+            );
+            g.append(
+                Text::new()
+                    .set("x", 18)
+                    .set("y", y + 12)
+                    .add(axes::text(label)),
+            );
+        }
+        let lines = self.entries.len() as u32;
+        Ok(Some((g, LEGEND_WIDTH, lines * LEGEND_LINE)))
+    }
+
+    fn vector_only(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use testing::{pcapng, scratch_dir};
+
+    #[test]
+    fn ranges() {
+        let range = |r: &str| PacketSelection::parse_range(r, "dns").map(|p| (p.first, p.last));
+        assert_eq!(range("5").unwrap(), (5, Some(5)));
+        assert_eq!(range("1-18").unwrap(), (1, Some(18)));
+        assert_eq!(range(" 2 - 4 ").unwrap(), (2, Some(4)));
+        assert_eq!(range("3-").unwrap(), (3, None));
+        assert_eq!(
+            PacketSelection::parse_range("3-", "dns").unwrap().filter,
+            "dns"
+        );
+        for garbage in &["0", "0-3", "5-3", "", "-", "-3", "a", "1-b", "1-2-3", "1.5"] {
+            assert!(range(garbage).is_err(), "{:?} was accepted", garbage);
+        }
+    }
+
+    fn datagram(spacing: usize, packets: &[&[u8]]) -> Datagram {
+        let name = format!("datagram-{}-{}", spacing, packets.len());
+        let path = scratch_dir(&name).join("packets.pcapng");
+        let packets: Vec<_> = packets.iter().map(|&p| (0, p)).collect();
+        fs::write(&path, pcapng(1, &packets)).unwrap();
+        Datagram {
+            packet_path: path,
+            packets: PacketSelection::parse_range("1-", "").unwrap(),
+            spacing,
+            gray_value: 100,
+            palette: None,
+        }
+    }
+
+    const GRAY: Rgb = (100, 100, 100);
+    const NONE: Rgb = (0, 0, 0);
+
+    #[test]
+    fn layout_of_full_lines() {
+        let (data, colors) = datagram(1, &[&[0xff; 32]]).layout().unwrap();
+        assert_eq!(data, vec![0xff; 32]);
+        assert_eq!(colors, vec![GRAY; 32]);
+    }
+
+    #[test]
+    fn layout_with_spacing() {
+        let (data, colors) = datagram(2, &[&[0xff; 32], &[1, 2, 3, 4, 5]])
+            .layout()
+            .unwrap();
+        assert_eq!(data.len(), 4 * BYTES_PER_LINE);
+        assert_eq!(colors.len(), data.len());
+        // the first packet, two empty lines, the second packet padded to a full line
+        assert_eq!(&data[..32], &[0xff; 32][..]);
+        assert_eq!(&colors[..32], &[GRAY; 32][..]);
+        assert_eq!(&data[32..96], &[0; 64][..]);
+        assert_eq!(&colors[32..96], &[NONE; 64][..]);
+        assert_eq!(&data[96..101], &[1, 2, 3, 4, 5]);
+        assert_eq!(&colors[96..101], &[GRAY; 5]);
+        assert_eq!(&data[101..], &[0; 27][..]);
+        assert_eq!(&colors[101..], &[NONE; 27][..]);
+    }
+
+    #[test]
+    fn layout_without_packets() {
+        let mut datagram = datagram(0, &[&[1, 2, 3]]);
+        datagram.packets.first = 2;
+        assert!(datagram.layout().is_err());
+    }
+}
@@ -0,0 +1,69 @@
+/// Everything that can go wrong while making the poster. Errors of single elements are reported,
+/// but don't stop the rest of the poster from being rendered.
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use filter::ParseError;
+
+#[derive(Debug)]
+pub enum Error {
+    /// reading or writing a file failed
+    Io(PathBuf, io::Error),
+    /// a capture file is not a pcapng file, or broken
+    Capture(PathBuf, String),
+    Filter(ParseError),
+    /// a cache entry can't be read or written
+    Cache(PathBuf, String),
+    /// the template is not valid XML, or the output can't be written
+    Template(PathBuf, String),
+    /// an element can't be drawn with its data and parameters
+    Render(String),
+    /// the poster description is invalid
+    Config(String),
+    Pdf(PathBuf, String),
+    /// some elements couldn't be drawn, the rest of the poster is fine
+    Elements(Vec<(String, Error)>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Wrap an I/O error together with the file it happened with.
+    pub fn io(path: &Path) -> impl FnOnce(io::Error) -> Error {
+        let path = path.to_path_buf();
+        move |e| Error::Io(path, e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            Error::Capture(ref path, ref e) => {
+                write!(f, "Could not read capture {}: {}", path.display(), e)
+            }
+            Error::Filter(ref e) => write!(f, "{}", e),
+            Error::Cache(ref path, ref e) => write!(f, "Cache entry {}: {}", path.display(), e),
+            Error::Template(ref path, ref e) => write!(f, "Template {}: {}", path.display(), e),
+            Error::Render(ref e) => write!(f, "{}", e),
+            Error::Config(ref e) => write!(f, "{}", e),
+            Error::Pdf(ref path, ref e) => write!(f, "Could not write {}: {}", path.display(), e),
+            Error::Elements(ref failed) => {
+                write!(f, "{} elements could not be drawn:", failed.len())?;
+                for (id, e) in failed {
+                    write!(f, "\n  {}: {}", id, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        Error::Filter(e)
+    }
+}
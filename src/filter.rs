@@ -0,0 +1,483 @@
+/// The subset of the Wireshark display filter language that we need to slice the captures by
+/// protocol, e.g. `udp.port==53 || tcp.port==53`, `tcp.port==443` or `udp`.
+///
+/// Supported are the protocols `eth`, `ip`, `ipv6`, `tcp` and `udp`, the fields listed in
+/// `Field::from_name`, the comparison operators `==`, `!=`, `<`, `<=`, `>`, `>=` (or `eq`, `ne`,
+/// ...), the logical operators `&&`, `||`, `!` (or `and`, `or`, `not`) and parentheses. An empty
+/// filter matches every packet.
+use std::fmt;
+use std::net::IpAddr;
+
+use packet::{Dissection, TransportHeader};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Eth,
+    Ip,
+    Ipv6,
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    IpProto,
+    IpAddr,
+    IpSrc,
+    IpDst,
+    Ipv6Addr,
+    Ipv6Src,
+    Ipv6Dst,
+    TcpPort,
+    TcpSrcPort,
+    TcpDstPort,
+    UdpPort,
+    UdpSrcPort,
+    UdpDstPort,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        Some(match name {
+            "ip.proto" => Field::IpProto,
+            "ip.addr" => Field::IpAddr,
+            "ip.src" => Field::IpSrc,
+            "ip.dst" => Field::IpDst,
+            "ipv6.addr" => Field::Ipv6Addr,
+            "ipv6.src" => Field::Ipv6Src,
+            "ipv6.dst" => Field::Ipv6Dst,
+            "tcp.port" => Field::TcpPort,
+            "tcp.srcport" => Field::TcpSrcPort,
+            "tcp.dstport" => Field::TcpDstPort,
+            "udp.port" => Field::UdpPort,
+            "udp.srcport" => Field::UdpSrcPort,
+            "udp.dstport" => Field::UdpDstPort,
+            _ => return None,
+        })
+    }
+
+    fn is_address(self) -> bool {
+        matches!(
+            self,
+            Field::IpAddr
+                | Field::IpSrc
+                | Field::IpDst
+                | Field::Ipv6Addr
+                | Field::Ipv6Src
+                | Field::Ipv6Dst
+        )
+    }
+
+    /// All values this field has in the packet. Fields like `tcp.port` can have more than one,
+    /// fields of layers that are missing have none.
+    fn values(self, d: &Dissection) -> Vec<Value> {
+        let ip = |version: u8| d.ip.as_ref().filter(|ip| ip.version == version);
+        let tcp = match d.transport {
+            Some(TransportHeader::Tcp { src_port, dst_port }) => Some((src_port, dst_port)),
+            _ => None,
+        };
+        let udp = match d.transport {
+            Some(TransportHeader::Udp { src_port, dst_port }) => Some((src_port, dst_port)),
+            _ => None,
+        };
+        let ports = |p: Option<(u16, u16)>, src: bool, dst: bool| match p {
+            Some((s, t)) => {
+                let mut v = Vec::new();
+                if src {
+                    v.push(Value::Int(u64::from(s)));
+                }
+                if dst {
+                    v.push(Value::Int(u64::from(t)));
+                }
+                v
+            }
+            None => Vec::new(),
+        };
+        let addrs = |version: u8, src: bool, dst: bool| match ip(version) {
+            Some(ip) => {
+                let mut v = Vec::new();
+                if src {
+                    v.push(Value::Addr(ip.src));
+                }
+                if dst {
+                    v.push(Value::Addr(ip.dst));
+                }
+                v
+            }
+            None => Vec::new(),
+        };
+        match self {
+            Field::IpProto => ip(4)
+                .map(|ip| vec![Value::Int(u64::from(ip.protocol))])
+                .unwrap_or_default(),
+            Field::IpAddr => addrs(4, true, true),
+            Field::IpSrc => addrs(4, true, false),
+            Field::IpDst => addrs(4, false, true),
+            Field::Ipv6Addr => addrs(6, true, true),
+            Field::Ipv6Src => addrs(6, true, false),
+            Field::Ipv6Dst => addrs(6, false, true),
+            Field::TcpPort => ports(tcp, true, true),
+            Field::TcpSrcPort => ports(tcp, true, false),
+            Field::TcpDstPort => ports(tcp, false, true),
+            Field::UdpPort => ports(udp, true, true),
+            Field::UdpSrcPort => ports(udp, true, false),
+            Field::UdpDstPort => ports(udp, false, true),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Value {
+    Int(u64),
+    Addr(IpAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// The empty filter.
+    Any,
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    /// Bare protocol name, true if the packet contains that layer.
+    Has(Protocol),
+    Compare(Field, CmpOp, Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub filter: String,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid filter \"{}\": {}", self.filter, self.message)
+    }
+}
+
+impl Filter {
+    pub fn parse(filter: &str) -> Result<Filter, ParseError> {
+        let err = |message: String| ParseError {
+            filter: filter.to_string(),
+            message,
+        };
+        let tokens = tokenize(filter).map_err(&err)?;
+        if tokens.is_empty() {
+            return Ok(Filter::Any);
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let res = parser.parse_or().map_err(&err)?;
+        match parser.peek() {
+            None => Ok(res),
+            Some(t) => Err(err(format!("unexpected {:?}", t))),
+        }
+    }
+
+    pub fn matches(&self, d: &Dissection) -> bool {
+        match *self {
+            Filter::Any => true,
+            Filter::Not(ref f) => !f.matches(d),
+            Filter::And(ref a, ref b) => a.matches(d) && b.matches(d),
+            Filter::Or(ref a, ref b) => a.matches(d) || b.matches(d),
+            Filter::Has(Protocol::Eth) => d.ethernet,
+            Filter::Has(Protocol::Ip) => d.ip.as_ref().is_some_and(|ip| ip.version == 4),
+            Filter::Has(Protocol::Ipv6) => d.ip.as_ref().is_some_and(|ip| ip.version == 6),
+            Filter::Has(Protocol::Tcp) => d.is_tcp(),
+            Filter::Has(Protocol::Udp) => d.is_udp(),
+            // like Wireshark, "!=" means that no occurrence of the field equals the value
+            Filter::Compare(field, CmpOp::Ne, ref value) => {
+                let values = field.values(d);
+                !values.is_empty() && values.iter().all(|v| v != value)
+            }
+            Filter::Compare(field, op, ref value) => field.values(d).iter().any(|v| match op {
+                CmpOp::Eq => v == value,
+                CmpOp::Lt => v < value,
+                CmpOp::Le => v <= value,
+                CmpOp::Gt => v > value,
+                CmpOp::Ge => v >= value,
+                CmpOp::Ne => unreachable!(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Cmp(CmpOp),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut res = Vec::new();
+    let chars: Vec<char> = s.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('=', Some('=')) => (Token::Cmp(CmpOp::Eq), 2),
+            ('!', Some('=')) => (Token::Cmp(CmpOp::Ne), 2),
+            ('<', Some('=')) => (Token::Cmp(CmpOp::Le), 2),
+            ('>', Some('=')) => (Token::Cmp(CmpOp::Ge), 2),
+            ('<', _) => (Token::Cmp(CmpOp::Lt), 1),
+            ('>', _) => (Token::Cmp(CmpOp::Gt), 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            (c, _) if c.is_alphanumeric() || c == '_' || c == '.' || c == ':' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric()
+                        || chars[i] == '_'
+                        || chars[i] == '.'
+                        || chars[i] == ':')
+                {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                res.push(match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "eq" => Token::Cmp(CmpOp::Eq),
+                    "ne" => Token::Cmp(CmpOp::Ne),
+                    "lt" => Token::Cmp(CmpOp::Lt),
+                    "le" => Token::Cmp(CmpOp::Le),
+                    "gt" => Token::Cmp(CmpOp::Gt),
+                    "ge" => Token::Cmp(CmpOp::Ge),
+                    _ => Token::Word(word),
+                });
+                continue;
+            }
+            (c, _) => return Err(format!("unexpected character '{}'", c)),
+        };
+        res.push(token);
+        i += len;
+    }
+    Ok(res)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let res = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        res
+    }
+
+    fn parse_or(&mut self) -> Result<Filter, String> {
+        let mut res = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            res = Filter::Or(Box::new(res), Box::new(self.parse_and()?));
+        }
+        Ok(res)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, String> {
+        let mut res = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            res = Filter::And(Box::new(res), Box::new(self.parse_unary()?));
+        }
+        Ok(res)
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Filter::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                let res = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(res),
+                    _ => Err("missing closing parenthesis".to_string()),
+                }
+            }
+            Some(Token::Word(name)) => self.parse_test(&name),
+            Some(t) => Err(format!("unexpected {:?}", t)),
+            None => Err("unexpected end of filter".to_string()),
+        }
+    }
+
+    /// A bare protocol name or a comparison of a field with a value.
+    fn parse_test(&mut self, name: &str) -> Result<Filter, String> {
+        let op = match self.peek() {
+            Some(&Token::Cmp(op)) => op,
+            _ => {
+                return match name {
+                    "eth" => Ok(Filter::Has(Protocol::Eth)),
+                    "ip" => Ok(Filter::Has(Protocol::Ip)),
+                    "ipv6" => Ok(Filter::Has(Protocol::Ipv6)),
+                    "tcp" => Ok(Filter::Has(Protocol::Tcp)),
+                    "udp" => Ok(Filter::Has(Protocol::Udp)),
+                    _ => Err(format!("unknown protocol \"{}\"", name)),
+                }
+            }
+        };
+        self.pos += 1;
+        let field = Field::from_name(name).ok_or(format!("unknown field \"{}\"", name))?;
+        let literal = match self.next() {
+            Some(Token::Word(w)) => w,
+            _ => return Err(format!("expected a value after \"{}\"", name)),
+        };
+        let value = if field.is_address() {
+            literal.parse().map(Value::Addr).ok()
+        } else if let Some(hex) = literal.strip_prefix("0x") {
+            u64::from_str_radix(hex, 16).map(Value::Int).ok()
+        } else {
+            literal.parse().map(Value::Int).ok()
+        };
+        match value {
+            Some(v) => Ok(Filter::Compare(field, op, v)),
+            None => Err(format!("invalid value \"{}\" for \"{}\"", literal, name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet::IpHeader;
+
+    fn udp(src: &str, dst: &str, src_port: u16, dst_port: u16) -> Dissection {
+        Dissection {
+            ethernet: true,
+            ip: Some(IpHeader {
+                version: 4,
+                protocol: 17,
+                src: src.parse().unwrap(),
+                dst: dst.parse().unwrap(),
+            }),
+            transport: Some(TransportHeader::Udp { src_port, dst_port }),
+            network_offset: Some(14),
+            transport_offset: Some(34),
+        }
+    }
+
+    fn matches(filter: &str, d: &Dissection) -> bool {
+        Filter::parse(filter).unwrap().matches(d)
+    }
+
+    #[test]
+    fn empty_filter() {
+        assert_eq!(Filter::parse("").unwrap(), Filter::Any);
+        assert_eq!(Filter::parse("  ").unwrap(), Filter::Any);
+    }
+
+    #[test]
+    fn precedence() {
+        // "and" binds tighter than "or"
+        let port = |p| Filter::Compare(Field::UdpPort, CmpOp::Eq, Value::Int(p));
+        assert_eq!(
+            Filter::parse("udp.port==53 || udp.port==5353 && ip").unwrap(),
+            Filter::Or(
+                Box::new(port(53)),
+                Box::new(Filter::And(
+                    Box::new(port(5353)),
+                    Box::new(Filter::Has(Protocol::Ip))
+                ))
+            )
+        );
+        assert_eq!(
+            Filter::parse("not (tcp or udp)").unwrap(),
+            Filter::Not(Box::new(Filter::Or(
+                Box::new(Filter::Has(Protocol::Tcp)),
+                Box::new(Filter::Has(Protocol::Udp))
+            )))
+        );
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(
+            Filter::parse("ip.proto eq 0x11").unwrap(),
+            Filter::Compare(Field::IpProto, CmpOp::Eq, Value::Int(17))
+        );
+        assert_eq!(
+            Filter::parse("ipv6.addr==::1").unwrap(),
+            Filter::Compare(
+                Field::Ipv6Addr,
+                CmpOp::Eq,
+                Value::Addr("::1".parse().unwrap())
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_filters() {
+        for f in &[
+            "udp.port==",
+            "udp.port==dns",
+            "ip.addr==53",
+            "foo",
+            "foo.bar==1",
+            "(udp",
+            "udp)",
+            "udp &&",
+            "udp.port=53",
+            "tcp.port==443 #",
+        ] {
+            assert!(Filter::parse(f).is_err(), "{} should be invalid", f);
+        }
+    }
+
+    #[test]
+    fn matching() {
+        let dns = udp("192.168.1.2", "9.9.9.9", 40000, 53);
+        assert!(matches("udp", &dns));
+        assert!(matches("ip && eth", &dns));
+        assert!(!matches("tcp", &dns));
+        assert!(!matches("ipv6", &dns));
+        assert!(matches("udp.port==53 || tcp.port==53", &dns));
+        assert!(matches("udp.dstport==53", &dns));
+        assert!(!matches("udp.srcport==53", &dns));
+        assert!(matches("udp.port>=40000", &dns));
+        assert!(matches("ip.dst==9.9.9.9", &dns));
+        assert!(!matches("ip.src==9.9.9.9", &dns));
+        assert!(matches("ip.proto==17", &dns));
+    }
+
+    #[test]
+    fn not_equal_like_wireshark() {
+        let dns = udp("192.168.1.2", "9.9.9.9", 40000, 53);
+        // no port is 443
+        assert!(matches("udp.port!=443", &dns));
+        // one of the ports is 53
+        assert!(!matches("udp.port!=53", &dns));
+        // but "not ==" is true
+        assert!(matches("!(udp.port==443)", &dns));
+        // packets without the field don't match
+        assert!(!matches("tcp.port!=443", &dns));
+        assert!(matches("!(tcp.port==443)", &dns));
+    }
+}
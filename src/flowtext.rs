@@ -0,0 +1,418 @@
+/// Inkscape's flowed text, `<flowRoot>`, never made it into SVG and usvg leaves it out, so the
+/// text of the template would be missing from the PDFs. It is turned into plain `<text>` with one
+/// `<tspan>` per line, wrapped into the same rectangle and placed where Inkscape puts it.
+use std::ops::Range;
+
+use svg2pdf::usvg::{self, fontdb, roxmltree};
+
+use axes::{escape, round};
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+
+/// Height of the em box above the baseline, and below it, for text in a font that can't be found.
+const DEFAULT_EM_BOX: (f64, f64) = (0.8, 0.2);
+
+/// A word with the style of every part that is in a `<flowSpan>`.
+type Word<'a> = Vec<(String, Option<&'a str>)>;
+
+/// `svg` with every `<flowRoot>` replaced by `<text>`. Lines are measured with the fonts of
+/// `options`, which are the ones the text is drawn with later.
+pub fn flowed_to_text(svg: &str, options: &usvg::Options) -> Result<String, String> {
+    let parsing = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..roxmltree::ParsingOptions::default()
+    };
+    let doc = roxmltree::Document::parse_with_options(svg, parsing).map_err(|e| e.to_string())?;
+    let mut replaced: Vec<(Range<usize>, String)> = Vec::new();
+    for node in doc.descendants().filter(|n| is(n, "flowRoot")) {
+        replaced.push((node.range(), text(node, options)?));
+    }
+    let mut res = String::new();
+    let mut end = 0;
+    for (range, text) in replaced {
+        res.push_str(&svg[end..range.start]);
+        res.push_str(&text);
+        end = range.end;
+    }
+    res.push_str(&svg[end..]);
+    Ok(res)
+}
+
+fn is(node: &roxmltree::Node, name: &str) -> bool {
+    node.tag_name().namespace() == Some(SVG_NS) && node.tag_name().name() == name
+}
+
+/// The `<text>` that looks like the `<flowRoot>` at `node`, with the same id, style and
+/// transform. Inkscape hides the lines that don't fit into the rectangle anymore, they are kept
+/// here, so no text gets lost.
+fn text(node: roxmltree::Node, options: &usvg::Options) -> Result<String, String> {
+    let id = node.attribute("id").unwrap_or("without id");
+    let rect = node
+        .children()
+        .find(|n| is(n, "flowRegion"))
+        .and_then(|region| region.children().find(|n| n.is_element()))
+        .filter(|shape| is(shape, "rect"))
+        .ok_or_else(|| format!("The flowed text {} has to flow into a rectangle", id))?;
+    let number = |name| {
+        rect.attribute(name)
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .unwrap_or(0.0)
+    };
+    let (x, top, width) = (number("x"), number("y"), number("width"));
+    let root_style = node.attribute("style").unwrap_or("");
+
+    let mut res = String::from("<text xml:space=\"preserve\"");
+    for a in node.attributes().filter(|a| a.namespace().is_none()) {
+        res.push_str(&format!(" {}=\"{}\"", a.name(), escape(a.value())));
+    }
+    res.push('>');
+    let mut baseline = None;
+    for para in node.children().filter(|n| is(n, "flowPara")) {
+        let para_style = para.attribute("style").unwrap_or("");
+        let styles = [root_style, para_style];
+        let font_size = property(&styles, "font-size")
+            .and_then(|s| s.trim_end_matches("px").parse::<f64>().ok())
+            .unwrap_or(16.0);
+        let line_height = line_height(property(&styles, "line-height"), font_size);
+        let (anchor, line_x) = match property(&styles, "text-align") {
+            Some("center") => ("middle", x + width / 2.0),
+            Some("end") | Some("right") => ("end", x + width),
+            _ => ("start", x),
+        };
+        let line_style = match para_style {
+            "" => format!("text-anchor:{}", anchor),
+            _ => format!("{};text-anchor:{}", para_style, anchor),
+        };
+        // like Inkscape, the line box is as high as the line height, with the em box of the
+        // font in its middle
+        let mut y = baseline.unwrap_or_else(|| {
+            let (ascent, descent) = em_box(&styles, options);
+            top - line_height / 2.0 + (ascent - descent) / 2.0 * font_size
+        });
+        let words = words(para);
+        let pens = pen_positions(&words, &styles, options);
+        let measure = |first: usize, last: usize| pens[last].1 - pens[first].0;
+        for range in wrap(&words, width, measure) {
+            let line = &words[range];
+            y += line_height;
+            if line.iter().all(|w| w.is_empty()) {
+                continue;
+            }
+            res.push_str(&format!(
+                "<tspan x=\"{}\" y=\"{}\" style=\"{}\">{}</tspan>",
+                round(line_x),
+                round(y),
+                escape(&line_style),
+                markup(line)
+            ));
+        }
+        baseline = Some(y);
+    }
+    res.push_str("</text>");
+    Ok(res)
+}
+
+/// The last value of the CSS property `name` in the `styles`, the later ones override the
+/// earlier ones.
+fn property<'a>(styles: &[&'a str], name: &str) -> Option<&'a str> {
+    styles
+        .iter()
+        .flat_map(|s| s.split(';'))
+        .filter_map(|d| {
+            let mut parts = d.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(n), Some(v)) if n.trim() == name => Some(v.trim()),
+                _ => None,
+            }
+        })
+        .next_back()
+}
+
+/// Distance between two baselines, `normal` is what Inkscape uses too.
+fn line_height(value: Option<&str>, font_size: f64) -> f64 {
+    let value = value.unwrap_or("normal");
+    let parsed = if let Some(percent) = value.strip_suffix('%') {
+        percent.parse::<f64>().map(|p| p / 100.0 * font_size)
+    } else if let Some(px) = value.strip_suffix("px") {
+        px.parse::<f64>()
+    } else {
+        value.parse::<f64>().map(|factor| factor * font_size)
+    };
+    parsed.unwrap_or(1.25 * font_size)
+}
+
+/// The words of a paragraph, split at every space, so joining them with spaces gives back the
+/// text, with all its spaces.
+fn words<'a>(para: roxmltree::Node<'a, '_>) -> Vec<Word<'a>> {
+    let mut words = vec![Word::new()];
+    for part in para.children() {
+        let style = if part.is_element() {
+            part.attribute("style")
+        } else {
+            None
+        };
+        let text: String = part
+            .descendants()
+            .filter(|n| n.is_text())
+            .filter_map(|n| n.text())
+            .collect();
+        for (i, piece) in text.split(' ').enumerate() {
+            if i > 0 {
+                words.push(Word::new());
+            }
+            if !piece.is_empty() {
+                words.last_mut().unwrap().push((piece.to_string(), style));
+            }
+        }
+    }
+    words
+}
+
+/// Break the words into lines that are at most `width` wide, as many words on every line as fit.
+/// A word that is wider on its own gets a line of its own. `measure` gives the width of the words
+/// from the first to the last index, both included.
+fn wrap<F>(words: &[Word], width: f64, measure: F) -> Vec<Range<usize>>
+where
+    F: Fn(usize, usize) -> f64,
+{
+    let mut lines: Vec<Range<usize>> = Vec::new();
+    lines.push(0..0);
+    for (i, word) in words.iter().enumerate() {
+        let line = lines.last_mut().unwrap();
+        line.end = i + 1;
+        // spaces may stick out at the end of a line
+        if line.len() > 1 && !word.is_empty() && measure(line.start, i) > width {
+            line.end = i;
+            lines.push(i..i + 1);
+        }
+    }
+    lines
+}
+
+/// The words as content of a `<tspan>`, with the parts in `<flowSpan>`s in `<tspan>`s of their
+/// own.
+fn markup(line: &[Word]) -> String {
+    let mut res = String::new();
+    for (i, word) in line.iter().enumerate() {
+        if i > 0 {
+            res.push(' ');
+        }
+        for (text, style) in word {
+            match *style {
+                Some(style) => res.push_str(&format!(
+                    "<tspan style=\"{}\">{}</tspan>",
+                    escape(style),
+                    escape(text)
+                )),
+                None => res.push_str(&escape(text)),
+            }
+        }
+    }
+    res
+}
+
+/// Where every word starts and ends, when usvg draws all of them in one line with the `styles`
+/// of the flowed text and its paragraph. All zero if the text can't be drawn.
+fn pen_positions(words: &[Word], styles: &[&str], options: &usvg::Options) -> Vec<(f64, f64)> {
+    // the character after the last word shows where the pen ends
+    let svg = format!(
+        "<svg xmlns=\"{}\" width=\"1\" height=\"1\"><text xml:space=\"preserve\" style=\"{}\">\
+         <tspan x=\"0\" y=\"0\" style=\"{}\">{}|</tspan></text></svg>",
+        SVG_NS,
+        escape(styles[0]),
+        escape(styles[1]),
+        markup(words)
+    );
+    let mut glyphs: Vec<(f32, usize)> = Vec::new();
+    if let Ok(tree) = usvg::Tree::from_str(&svg, options) {
+        for node in tree.root().children() {
+            if let usvg::Node::Text(ref text) = *node {
+                for span in text.layouted() {
+                    let positioned = span.positioned_glyphs.iter();
+                    glyphs.extend(positioned.map(|g| (g.transform().tx, g.text.chars().count())));
+                }
+            }
+        }
+    }
+    // from left to right, whatever span the glyphs are in
+    glyphs.sort_by(|a, b| a.0.total_cmp(&b.0));
+    // the position of the first character of every glyph, ligatures have several
+    let mut x = Vec::new();
+    for (tx, chars) in glyphs {
+        x.push(Some(tx as f64));
+        x.extend(std::iter::repeat_n(None, chars.saturating_sub(1)));
+    }
+    let at = |i: usize| x.get(i).cloned().flatten().unwrap_or(0.0);
+    let mut res = Vec::new();
+    let mut start = 0;
+    for word in words {
+        let end = start + word.iter().map(|(t, _)| t.chars().count()).sum::<usize>();
+        // the space or the end mark after the word
+        res.push((at(start), at(end)));
+        start = end + 1;
+    }
+    res
+}
+
+/// Height of the em box above and below the baseline, as a fraction of the font size. Inkscape
+/// takes the typographic ascender and descender of the font, scaled so they add up to one.
+fn em_box(styles: &[&str], options: &usvg::Options) -> (f64, f64) {
+    let families: Vec<String> = property(styles, "font-family")
+        .unwrap_or("")
+        .split(',')
+        .map(|f| f.trim().trim_matches(|c| c == '\'' || c == '"').to_string())
+        .filter(|f| !f.is_empty())
+        .collect();
+    let mut query_families: Vec<fontdb::Family> = families
+        .iter()
+        .map(|f| match f.as_str() {
+            "serif" => fontdb::Family::Serif,
+            "sans-serif" => fontdb::Family::SansSerif,
+            "monospace" => fontdb::Family::Monospace,
+            name => fontdb::Family::Name(name),
+        })
+        .collect();
+    // the fallback of usvg
+    query_families.push(fontdb::Family::Serif);
+    let weight = match property(styles, "font-weight") {
+        Some("bold") => fontdb::Weight::BOLD,
+        Some(w) => w.parse().map(fontdb::Weight).unwrap_or_default(),
+        None => fontdb::Weight::NORMAL,
+    };
+    let style = match property(styles, "font-style") {
+        Some("italic") => fontdb::Style::Italic,
+        Some("oblique") => fontdb::Style::Oblique,
+        _ => fontdb::Style::Normal,
+    };
+    let query = fontdb::Query {
+        families: &query_families,
+        weight,
+        style,
+        ..fontdb::Query::default()
+    };
+    let metrics = options.fontdb.query(&query).and_then(|id| {
+        options.fontdb.with_face_data(id, |data, index| {
+            let face = ttf_parser::Face::parse(data, index).ok()?;
+            let ascent = face.typographic_ascender().unwrap_or(face.ascender());
+            let descent = face.typographic_descender().unwrap_or(face.descender());
+            Some((f64::from(ascent), -f64::from(descent)))
+        })?
+    });
+    match metrics {
+        Some((ascent, descent)) if ascent + descent > 0.0 => {
+            (ascent / (ascent + descent), descent / (ascent + descent))
+        }
+        _ => DEFAULT_EM_BOX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn para(content: &str) -> String {
+        let svg = format!("<flowPara xmlns=\"{}\">{}</flowPara>", SVG_NS, content);
+        let doc = roxmltree::Document::parse(&svg).unwrap();
+        markup(&words(doc.root_element()))
+    }
+
+    #[test]
+    fn words_keep_spaces_and_spans() {
+        assert_eq!(para("two words"), "two words");
+        assert_eq!(para(" many   spaces "), " many   spaces ");
+        assert_eq!(para(""), "");
+        assert_eq!(para("a &amp; b"), "a &amp; b");
+        assert_eq!(
+            para("see <flowSpan style=\"font-style:italic\">example.org</flowSpan>, too"),
+            "see <tspan style=\"font-style:italic\">example.org</tspan>, too"
+        );
+        // a word that is partly in a span
+        assert_eq!(
+            para("x<flowSpan style=\"a\">y z</flowSpan>"),
+            "x<tspan style=\"a\">y</tspan> <tspan style=\"a\">z</tspan>"
+        );
+    }
+
+    #[test]
+    fn wrapping() {
+        // two spaces in a row leave an empty word
+        let words: Vec<Word> = ["aaa", "bb", "", "c", "dddddd", "e"]
+            .iter()
+            .map(|s| match *s {
+                "" => vec![],
+                s => vec![(s.to_string(), None)],
+            })
+            .collect();
+        // one unit per character, and one per space
+        let measure = |first: usize, last: usize| {
+            let line = &words[first..=last];
+            let chars: usize = line.iter().flatten().map(|(text, _)| text.len()).sum();
+            (chars + line.len() - 1) as f64
+        };
+        assert_eq!(wrap(&words, 6.0, measure), vec![0..3, 3..4, 4..5, 5..6]);
+        assert_eq!(wrap(&words, 9.0, measure), vec![0..4, 4..6]);
+        assert_eq!(wrap(&words, 100.0, measure), vec![0..6]);
+        assert_eq!(wrap(&[], 100.0, measure), vec![0..0]);
+    }
+
+    #[test]
+    fn styles() {
+        let styles = [
+            "font-size:20px;line-height:25px",
+            "line-height: 150% ;fill:red",
+        ];
+        assert_eq!(property(&styles, "line-height"), Some("150%"));
+        assert_eq!(property(&styles, "font-size"), Some("20px"));
+        assert_eq!(property(&styles, "font-family"), None);
+        assert_eq!(line_height(Some("150%"), 20.0), 30.0);
+        assert_eq!(line_height(Some("25px"), 20.0), 25.0);
+        assert_eq!(line_height(Some("1.5"), 20.0), 30.0);
+        assert_eq!(line_height(Some("normal"), 20.0), 25.0);
+        assert_eq!(line_height(None, 20.0), 25.0);
+    }
+
+    const FLOWED: &str = "<svg xmlns=\"http://www.w3.org/2000/svg\"><rect id=\"before\"/>\
+        <flowRoot id=\"flow\" transform=\"translate(1,2)\" style=\"font-size:20px;line-height:30px\">\
+        <flowRegion><rect x=\"10\" y=\"100\" width=\"200\" height=\"50\"/></flowRegion>\
+        <flowPara>first &lt;line&gt;</flowPara><flowPara/>\
+        <flowPara style=\"text-align:center\">third</flowPara></flowRoot>\
+        <rect id=\"after\"/></svg>";
+
+    #[test]
+    fn flowed_text_becomes_text() {
+        // without fonts, nothing is wrapped and the default em box is used
+        let text = flowed_to_text(FLOWED, &usvg::Options::default()).unwrap();
+        assert!(!text.contains("<flow"));
+        assert!(text.starts_with(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\"><rect id=\"before\"/><text \
+             xml:space=\"preserve\" id=\"flow\" transform=\"translate(1,2)\" \
+             style=\"font-size:20px;line-height:30px\">"
+        ));
+        assert!(text.ends_with("</text><rect id=\"after\"/></svg>"));
+        // the first baseline is half a line and 0.3 em below the top, the empty paragraph
+        // takes a line
+        assert!(text.contains(
+            "<tspan x=\"10\" y=\"121\" style=\"text-anchor:start\">first &lt;line&gt;</tspan>\
+             <tspan x=\"110\" y=\"181\" style=\"text-align:center;text-anchor:middle\">third\
+             </tspan></text>"
+        ));
+        // and the result can be drawn
+        usvg::Tree::from_str(&text, &usvg::Options::default()).unwrap();
+    }
+
+    #[test]
+    fn only_rectangles() {
+        let circle = FLOWED.replace(
+            "<rect x=\"10\" y=\"100\" width=\"200\" height=\"50\"/>",
+            "<circle r=\"10\"/>",
+        );
+        let err = flowed_to_text(&circle, &usvg::Options::default()).unwrap_err();
+        assert_eq!(err, "The flowed text flow has to flow into a rectangle");
+    }
+
+    #[test]
+    fn without_flowed_text() {
+        let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"><text>as it is</text></svg>";
+        assert_eq!(flowed_to_text(svg, &usvg::Options::default()).unwrap(), svg);
+    }
+}
@@ -1,299 +1,1121 @@
 extern crate bincode;
-extern crate csv;
 extern crate png;
 extern crate xz2;
 
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
+use std::net::IpAddr;
+use std::sync::Mutex;
 
-use std::time::{Duration, SystemTime};
+use std::time::SystemTime;
 
 // For reading and opening files
-use std::io::BufWriter;
-use std::path::{Path, PathBuf};
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
 // To use encoder.set()
 use png::HasParameters;
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use bincode::{deserialize_from, serialize_into};
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
 
-use bincode::{deserialize, serialize};
+use cachable::{CachableData, CachablePNG, Cache, CacheKey};
+use capture_set::{self, CaptureSet, Interval};
+use error::{Error, Result};
 
-use cachable::{CachableData, CachablePNG};
+use filter::Filter;
+use packet::{dissect, Dissection, Flow};
+use parallel;
 
-use std::process::Command;
+use axes;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 
-#[derive(Debug, Deserialize)]
-struct CSVLine {
-    time_string: String,
-    len: usize,
+use svg::node::element::path::Data;
+use svg::node::element::{Group, Path, Rectangle};
+use svg::node::Node;
+
+pub type Rgb = (u8, u8, u8);
+
+/// Version of the `.vec` format, bump it whenever `VecHeader` or the data changes.
+const VEC_FORMAT_VERSION: u32 = 4;
+
+/// Start of every `.vec` file, says what the data is, so it can be checked before it is used.
+/// The header and the `Values` after it are bincode, and the whole file is xz compressed.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct VecHeader {
+    version: u32,
+    buckets: u64,
+    /// seconds and nanoseconds since the unix epoch
+    start_time: (u64, u32),
+    end_time: (u64, u32),
+    metric: Metric,
+    filter: String,
+}
+
+/// What is summed up, counted or measured in every bucket of a histogram.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// bits transferred
+    #[default]
+    Bits,
+    Bytes,
+    Packets,
+    /// distinct conversations, see `Dissection::flow`
+    Flows,
+    /// distinct addresses of hosts on the internet, see `Dissection::remote_hosts`
+    RemoteHosts,
+    /// mean frame size in bytes
+    AvgPacketSize,
+    /// largest frame size in bytes
+    MaxPacketSize,
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Metric::Bits => "bits",
+            Metric::Bytes => "bytes",
+            Metric::Packets => "packets",
+            Metric::Flows => "flows",
+            Metric::RemoteHosts => "remote hosts",
+            Metric::AvgPacketSize => "bytes per packet on average",
+            Metric::MaxPacketSize => "bytes in the largest packet",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Metric {
+    /// Whether the values of packets that are split into several histograms, by filters that
+    /// exclude each other, add up to the value of all of them, so they can be stacked.
+    pub fn is_additive(self) -> bool {
+        match self {
+            Metric::Bits | Metric::Bytes | Metric::Packets | Metric::Flows => true,
+            Metric::RemoteHosts | Metric::AvgPacketSize | Metric::MaxPacketSize => false,
+        }
+    }
+
+    /// Unit of the values on an axis, and whether they are divided by the length of a bucket to
+    /// get a rate.
+    fn unit(self) -> (&'static str, bool) {
+        match self {
+            Metric::Bits => ("bit/s", true),
+            Metric::Bytes => ("B/s", true),
+            Metric::Packets => ("pkt/s", true),
+            Metric::Flows => ("flows", false),
+            Metric::RemoteHosts => ("hosts", false),
+            Metric::AvgPacketSize | Metric::MaxPacketSize => ("B", false),
+        }
+    }
+}
+
+/// The buckets of a `HistogramData`, and how many matching packets were outside of its time
+/// window, so they are in no bucket.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Values {
+    pub buckets: Vec<u64>,
+    pub before: u64,
+    pub after: u64,
+    /// when the captures were running, see `CaptureSet::max_silence`
+    pub covered: Vec<Interval>,
+}
+
+/// Something that is only counted once per bucket.
+#[derive(PartialEq, Eq, Hash)]
+enum Seen {
+    Flow(Flow),
+    Host(IpAddr),
+}
+
+/// The buckets of one `HistogramData`, while its data file is read.
+struct Buckets {
+    metric: Metric,
+    values: Vec<u64>,
+    packets: Vec<u64>,
+    seen: Vec<HashSet<Seen>>,
+    before: u64,
+    after: u64,
+}
+
+impl Buckets {
+    fn new(metric: Metric, width: usize) -> Buckets {
+        Buckets {
+            metric,
+            values: vec![0; width],
+            packets: vec![0; width],
+            seen: (0..width).map(|_| HashSet::new()).collect(),
+            before: 0,
+            after: 0,
+        }
+    }
+
+    /// Count a packet of `len` bytes into its bucket.
+    fn add(&mut self, slot: Slot, len: u64, dissection: &Dissection) {
+        let i = match slot {
+            Slot::Before => {
+                self.before += 1;
+                return;
+            }
+            Slot::After => {
+                self.after += 1;
+                return;
+            }
+            Slot::Bucket(i) => i,
+        };
+        self.packets[i] += 1;
+        match self.metric {
+            Metric::Bits => self.values[i] += len * 8,
+            Metric::Bytes | Metric::AvgPacketSize => self.values[i] += len,
+            Metric::Packets => self.values[i] += 1,
+            Metric::MaxPacketSize => self.values[i] = self.values[i].max(len),
+            Metric::Flows => {
+                if let Some(flow) = dissection.flow() {
+                    if self.seen[i].insert(Seen::Flow(flow)) {
+                        self.values[i] += 1;
+                    }
+                }
+            }
+            Metric::RemoteHosts => {
+                for host in dissection.remote_hosts() {
+                    if self.seen[i].insert(Seen::Host(host)) {
+                        self.values[i] += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish(self, covered: Vec<Interval>) -> Values {
+        let buckets = match self.metric {
+            Metric::AvgPacketSize => self
+                .values
+                .iter()
+                .zip(&self.packets)
+                .map(|(&sum, &n)| sum.checked_div(n).unwrap_or(0))
+                .collect(),
+            _ => self.values,
+        };
+        Values {
+            buckets,
+            before: self.before,
+            after: self.after,
+            covered,
+        }
+    }
 }
 
 // that part of histogram, that, if changed, would require the expensive rereading of all datafiles
 // again
-#[derive(Hash)]
+#[derive(Clone)]
 pub struct HistogramData {
     pub filter: Option<String>,
     pub filter_description: Option<String>,
     pub start_time: SystemTime,
     pub end_time: SystemTime,
     pub width: usize,
-    pub do_pps: bool,
-    pub data_file: &'static Path,
+    pub metric: Metric,
+    /// all captures of a device, read as one
+    pub captures: CaptureSet,
 }
 
-impl CachableData for HistogramData {
-    fn data_cached(&self) -> Vec<u64> {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        let hash: u64 = hasher.finish();
-        let cache_path = PathBuf::from(&format!(
-            "./cache/{}-{}.vec",
-            &hash.to_string(),
+impl HistogramData {
+    pub fn cache_key(&self) -> CacheKey {
+        let nanos = |t: SystemTime| {
+            t.duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        };
+        let mut key = CacheKey::new("histogram data");
+        key.add("filter", self.filter.as_deref().unwrap_or(""));
+        key.add("start_time", &nanos(self.start_time));
+        key.add("end_time", &nanos(self.end_time));
+        key.add("width", &self.width);
+        key.add("metric", &self.metric);
+        key.add("dedup_window", &format!("{:?}", self.captures.dedup_window));
+        key.add("max_silence", &format!("{:?}", self.captures.max_silence));
+        for f in &self.captures.files {
+            key.add_input("data_file", f);
+        }
+        key
+    }
+
+    pub fn cache_path(&self, cache: &Cache) -> PathBuf {
+        cache.path(&format!(
+            "{}-{}.vec",
+            self.cache_key().hex(),
             self.filter_description
                 .clone()
                 .unwrap_or("unnamed".to_string()),
-        ));
-        if cache_path.exists() {
+        ))
+    }
+
+    /// What the header of our `.vec` file has to say.
+    fn vec_header(&self) -> VecHeader {
+        let unix = |t: SystemTime| {
+            let d = t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+            (d.as_secs(), d.subsec_nanos())
+        };
+        VecHeader {
+            version: VEC_FORMAT_VERSION,
+            buckets: self.width as u64,
+            start_time: unix(self.start_time),
+            end_time: unix(self.end_time),
+            metric: self.metric,
+            filter: self.filter.clone().unwrap_or_default(),
+        }
+    }
+
+    fn write_cache(&self, cache: &Cache, values: &Values) -> Result<()> {
+        let path = self.cache_path(cache);
+        let file: File = File::create(&path).map_err(Error::io(&path))?;
+        let cache_error = |e: bincode::Error| Error::Cache(path.clone(), e.to_string());
+        let mut xz = XzEncoder::new(BufWriter::new(file), 6);
+        serialize_into(&mut xz, &self.vec_header()).map_err(cache_error)?;
+        serialize_into(&mut xz, values).map_err(cache_error)?;
+        xz.finish().map_err(Error::io(&path))?;
+        cache.record(&path, &self.cache_key());
+        Ok(())
+    }
+
+    /// The cached data, opened after checking that its header says it is what we asked for. The
+    /// values follow, only what is read gets decompressed.
+    fn open_cache(&self, cache: &Cache) -> Result<XzDecoder<BufReader<File>>> {
+        let path = self.cache_path(cache);
+        let file = File::open(&path).map_err(Error::io(&path))?;
+        let cache_error = |message: String| Error::Cache(path.clone(), message);
+        let mut xz = XzDecoder::new(BufReader::new(file));
+        let header: VecHeader =
+            deserialize_from(&mut xz).map_err(|e| cache_error(e.to_string()))?;
+        if header.version != VEC_FORMAT_VERSION {
+            return Err(cache_error(format!(
+                "format version {} instead of {}",
+                header.version, VEC_FORMAT_VERSION
+            )));
+        }
+        if header != self.vec_header() {
+            return Err(cache_error(format!(
+                "header {:?} doesn't match {:?}",
+                header,
+                self.vec_header()
+            )));
+        }
+        Ok(xz)
+    }
+
+    /// The cached data, if it is there and its header says it is what we asked for.
+    fn read_cache(&self, cache: &Cache) -> Result<Values> {
+        let path = self.cache_path(cache);
+        let cache_error = |message: String| Error::Cache(path.clone(), message);
+        let mut xz = self.open_cache(cache)?;
+        let values: Values = deserialize_from(&mut xz).map_err(|e| cache_error(e.to_string()))?;
+        if values.buckets.len() != self.width {
+            return Err(cache_error(format!(
+                "{} buckets instead of {}",
+                values.buckets.len(),
+                self.width
+            )));
+        }
+        Ok(values)
+    }
+
+    /// True if there is a usable entry in the cache, judged by its header.
+    fn is_cached(&self, cache: &Cache) -> bool {
+        if !cache.is_fresh(&self.cache_path(cache)) {
+            return false;
+        }
+        match self.open_cache(cache) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("{}, computing it again", e);
+                false
+            }
+        }
+    }
+
+    /// True if all the packets that `other` needs are the same as the ones we need, so both can
+    /// be computed in the same pass over the data file.
+    fn same_pass(&self, other: &HistogramData) -> bool {
+        self.captures == other.captures
+            && self.start_time == other.start_time
+            && self.end_time == other.end_time
+            && self.width == other.width
+    }
+
+    /// Make sure every given `HistogramData` is in the cache. Those that are missing are grouped
+    /// by data file, time range and width, and every group is computed in one pass, up to `jobs`
+    /// groups at the same time. Groups that fail are skipped, the elements that need them report
+    /// the error when they are drawn.
+    pub fn fill_cache(cache: &Cache, data: &[&HistogramData], jobs: usize) {
+        let mut groups: Vec<Vec<&HistogramData>> = Vec::new();
+        // the same HistogramData can be part of several histograms
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+        for d in data {
+            if !seen.insert(d.cache_path(cache)) || d.is_cached(cache) {
+                continue;
+            }
+            match groups.iter_mut().find(|g| g[0].same_pass(d)) {
+                Some(g) => g.push(d),
+                None => groups.push(vec![d]),
+            }
+        }
+        let name = |g: &Vec<&HistogramData>| g[0].captures.describe();
+        parallel::map(jobs, "Histogram data", &groups, name, |g| {
             println!(
-                "Cache hit for {} for data_file {}",
-                &cache_path.to_str().unwrap(),
-                &self.data_file.to_str().unwrap()
+                "Computing {} histogram data sets in one pass over {}",
+                g.len(),
+                g[0].captures.describe()
             );
-            deserialize(&std::fs::read(&cache_path).unwrap()).unwrap()
-        } else {
-            use std::io::Write;
-            println!("Cache miss for {}", &cache_path.to_str().unwrap());
-            let res: Vec<u64> = self.data_uncached();
-            let bytes: Vec<u8> = serialize(&res).unwrap();
-            let mut file: File = File::create(cache_path).unwrap();
-            file.write_all(&bytes).unwrap();
-            res
-        }
+            let results = match HistogramData::data_uncached_batch(g) {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            for (d, res) in g.iter().zip(results) {
+                if let Err(e) = d.write_cache(cache, &res) {
+                    println!("{}", e);
+                }
+            }
+        });
     }
-    /// Read one data file, filter it optionally, go through every packet and sorts
-    /// them into a bucket depending on its timestamp.
-    fn data_uncached(&self) -> Vec<u64> {
-        let mut histo_data: Vec<u64> = vec![0; self.width];
+
+    /// Read one data file, go through every packet, test it against the filters of all given
+    /// `HistogramData` and sort it into a bucket of every one that matches, depending on its
+    /// timestamp. All of them need to have the same data file, time range and width.
+    /// Packets outside of the time window are only counted.
+    pub fn data_uncached_batch(batch: &[&HistogramData]) -> Result<Vec<Values>> {
+        let first = match batch.first() {
+            Some(d) => d,
+            None => return Ok(Vec::new()),
+        };
+        if !batch.iter().all(|d| first.same_pass(d)) {
+            return Err(Error::Render(
+                "Histogram data in one batch has to share data file, time range and width"
+                    .to_string(),
+            ));
+        }
+        let mut buckets: Vec<Buckets> = batch
+            .iter()
+            .map(|d| Buckets::new(d.metric, first.width))
+            .collect();
         let mut count: u32 = 0;
         let mut byte_sum: u64 = 0;
 
-        // path to CSV file, that is the result of the filter application
-        let filtered_pcap: PathBuf = {
-            let f = self.filter.clone().unwrap_or("".to_string());
-            std::fs::create_dir("./tmp").ok();
-            let mut hasher = DefaultHasher::new();
-            self.data_file.hash(&mut hasher);
-            let hash: u64 = hasher.finish();
-            let res_path: PathBuf = PathBuf::from(format!(
-                "./tmp/filter-{}-{}.csv",
-                self.filter_description
-                    .clone()
-                    .unwrap_or("unnamed".to_string()),
-                hash.to_string()
-            ));
-            let cmd = format!(
-                    "tshark -T fields -E separator='|' -e frame.time_epoch -e frame.len -r '{orig}' '{filter}' > {new}",
-                    orig = self.data_file.display(),
-                    new = res_path.display(),
-                    filter = f,
-                );
-            println!("Running command: {}", &cmd);
-            let status = Command::new("sh")
-                .arg("-c")
-                .arg(cmd)
-                .status()
-                .expect("Faiil");
-            assert!(status.success(), "Tshark filtering failed!");
-            res_path
-        };
+        let mut filters: Vec<Filter> = Vec::new();
+        for d in batch {
+            filters.push(match d.filter {
+                Some(ref f) => Filter::parse(f)?,
+                None => Filter::Any,
+            });
+        }
 
-        println!(
-            "Reading CSV with filter results of {} ...",
-            &self.data_file.display()
-        );
-        let mut rdr = csv::ReaderBuilder::new()
-            .delimiter(b'|')
-            .has_headers(false)
-            .from_path(&filtered_pcap)
-            .unwrap();
+        for d in batch {
+            println!(
+                "Filtering {} with \"{}\" ...",
+                first.captures.describe(),
+                d.filter.clone().unwrap_or("".to_string())
+            );
+        }
+        let mut packets = first.captures.open()?;
 
         // sort packets into buckets in histo_data
-        for result in rdr.deserialize() {
-            let packet: CSVLine = result.unwrap();
+        for pkt in packets.by_ref() {
+            let pkt = pkt?;
+            // pcarp only gives us the captured bytes, which is the frame length as long as the
+            // snaplen wasn't hit
+            let len = pkt.data.len();
             count += 1;
-            // println!("{}", count);
-            byte_sum += packet.len as u64;
-            let timestamp = {
-                let split: Vec<&str> = packet.time_string.splitn(2, ".").collect();
-                SystemTime::UNIX_EPOCH
-                    + Duration::new(
-                        // std::u64::from_str_radix(s, 10),
-                        // std::u32::from_str_radix(ns, 10),
-                        split[0].parse().unwrap(),
-                        split[1].parse().unwrap(),
-                    )
-            };
-            let index = match get_bucket(self.width, self.start_time, self.end_time, timestamp) {
-                Some(i) => i,
+            byte_sum += len as u64;
+            let timestamp = match pkt.timestamp {
+                Some(t) => t,
                 None => continue,
             };
-            histo_data[index] += if self.do_pps {
-                1
-            } else {
-                packet.len as u64 * 8 // bits transfered
+            let slot = get_bucket(first.width, first.start_time, first.end_time, timestamp);
+            let dissection = dissect(pkt.link_type, &pkt.data);
+            for (filter, b) in filters.iter().zip(&mut buckets) {
+                if filter.matches(&dissection) {
+                    b.add(slot, len as u64, &dissection);
+                }
+            }
+        }
+        println!("{} Bytes, {} Packets in whole dataset.", byte_sum, count);
+        if packets.duplicates > 0 {
+            println!(
+                "{} packets of {} were captured twice and are counted once",
+                packets.duplicates,
+                first.captures.describe()
+            );
+        }
+        let covered = packets.coverage();
+        for (from, to) in capture_set::gaps(covered.clone()) {
+            println!(
+                "No capture of {} from {} to {}",
+                first.captures.device,
+                DateTime::<Utc>::from(from),
+                DateTime::<Utc>::from(to)
+            );
+        }
+        Ok(buckets
+            .into_iter()
+            .map(|b| b.finish(covered.clone()))
+            .collect())
+    }
+
+    /// How much of the time window the captures cover, from 0 to 1. Only from the cache.
+    pub fn coverage(&self, cache: &Cache) -> Result<f64> {
+        let values = self.read_cache(cache)?;
+        Ok(capture_set::fraction(
+            &values.covered,
+            self.start_time,
+            self.end_time,
+        ))
+    }
+
+    /// Buckets in which none of the captures was running.
+    pub fn uncovered(&self, values: &Values) -> Vec<bool> {
+        let mut res = vec![true; self.width];
+        for &(from, to) in &values.covered {
+            let slot = |t| get_bucket(self.width, self.start_time, self.end_time, t);
+            let first = match slot(from) {
+                Slot::Before => 0,
+                Slot::Bucket(i) => i,
+                Slot::After => continue,
+            };
+            let last = match slot(to) {
+                Slot::Before => continue,
+                Slot::Bucket(i) => i,
+                Slot::After => self.width - 1,
             };
+            for u in &mut res[first..=last] {
+                *u = false;
+            }
+        }
+        res
+    }
+
+    /// Say how many packets were left out, because they are outside of the time window. Only
+    /// from the cache.
+    pub fn report_outside(&self, cache: &Cache) -> Result<()> {
+        let values = self.read_cache(cache)?;
+        if values.before > 0 || values.after > 0 {
+            println!(
+                "{}: {} packets matching \"{}\" before and {} after the time window are not counted",
+                self.captures.describe(),
+                values.before,
+                self.filter.as_deref().unwrap_or(""),
+                values.after
+            );
         }
-        // delete the data file if it was temporary
-        match self.filter {
-            Some(_) => {
-                assert!(
-                    filtered_pcap.starts_with("./tmp"),
-                    "filtered_cap is not in tmp???"
-                );
-                std::fs::remove_file(filtered_pcap).unwrap();
+        Ok(())
+    }
+}
+
+impl CachableData for HistogramData {
+    type Data = Values;
+
+    fn data_cached(&self, cache: &Cache) -> Result<Values> {
+        let cache_path = self.cache_path(cache);
+        if cache.is_fresh(&cache_path) {
+            match self.read_cache(cache) {
+                Ok(values) => {
+                    println!(
+                        "Cache hit for {} for {}",
+                        &cache_path.to_str().unwrap(),
+                        self.captures.describe()
+                    );
+                    return Ok(values);
+                }
+                Err(e) => println!("{}, computing it again", e),
             }
-            // DO NOT REMOVE THE ORIGINAL DATA FILE
-            None => {}
         }
-        println!("{} Bytes, {} Packets in whole dataset.", byte_sum, count);
-        histo_data
+        println!("Cache miss for {}", &cache_path.to_str().unwrap());
+        let values = self.data_uncached()?;
+        self.write_cache(cache, &values)?;
+        Ok(values)
+    }
+    /// Read one data file, filter it optionally, go through every packet and sorts
+    /// them into a bucket depending on its timestamp.
+    fn data_uncached(&self) -> Result<Values> {
+        Ok(HistogramData::data_uncached_batch(&[self])?.remove(0))
     }
 }
 
-pub struct Histogram {
-    pub do_log: bool,
-    pub yscale: f64,
-    pub data: Vec<Box<HistogramData>>,
-    pub color: (u8, u8, u8),
+/// How values are mapped to the height of the bars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scale {
+    Linear,
+    /// logarithmic, starting at the power of ten below the smallest value
+    Log,
+    /// square root, between the two, and still zero at zero
+    Sqrt,
 }
 
-impl Hash for Histogram {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.do_log.hash(state);
-        // f64 is not hashable m(
-        self.yscale.to_string().hash(state);
-        self.data.hash(state);
-        self.color.hash(state);
-        // implementation
-        "20".hash(state);
+impl Scale {
+    /// The height of a bar for `v`, in units of the scale. `lowest` is the smallest value the
+    /// scale has to show.
+    pub fn apply(self, v: f64, lowest: u64) -> f64 {
+        if v <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            Scale::Linear => v,
+            Scale::Sqrt => v.sqrt(),
+            Scale::Log => v.log10() - self.bottom(lowest).log10(),
+        }
+    }
+
+    /// The value at height `h` in units of the scale.
+    fn invert(self, h: f64, lowest: u64) -> f64 {
+        match self {
+            Scale::Linear => h,
+            Scale::Sqrt => h * h,
+            Scale::Log => self.bottom(lowest) * 10f64.powf(h),
+        }
+    }
+
+    /// The value at the bottom of the scale.
+    fn bottom(self, lowest: u64) -> f64 {
+        match self {
+            Scale::Log => 10f64.powf((lowest.max(1) as f64).log10().floor()),
+            _ => 0.0,
+        }
     }
 }
 
-impl CachablePNG for Histogram {
-    fn png_cached(&self) -> Result<PathBuf, &str> {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        let hash: u64 = hasher.finish();
-        let path = PathBuf::from(&format!("./cache/{}.png", &hash.to_string()));
-        if path.exists() {
-            println!("Cache hit for {}", &path.to_str().unwrap());
-            Ok(path)
-        } else {
-            println!("Cache miss for {}", &path.to_str().unwrap());
-            self.png_uncached(path)
+/// Height of the histograms in the original template, for elements without a size.
+pub const DEFAULT_HEIGHT: usize = 661;
+
+/// Smallest value above zero and largest value of some buckets, `None` if they are all empty.
+pub fn value_range(data: &[u64]) -> Option<(u64, u64)> {
+    let min = data.iter().cloned().filter(|&v| v > 0).min()?;
+    let max = data.iter().cloned().max()?;
+    Some((min, max))
+}
+
+/// The smallest and largest value of two ranges.
+pub fn merge_ranges(a: Option<(u64, u64)>, b: Option<(u64, u64)>) -> Option<(u64, u64)> {
+    match (a, b) {
+        (Some((min, max)), Some((bmin, bmax))) => Some((min.min(bmin), max.max(bmax))),
+        (r, None) | (None, r) => r,
+    }
+}
+
+/// The smallest value the scale shows, and the pixels per unit of the scale. Unless `yscale` is
+/// given, the largest value of `range` fills the whole `height`.
+pub fn fit_scale(
+    scale: Scale,
+    yscale: Option<f64>,
+    height: usize,
+    range: Option<(u64, u64)>,
+) -> Result<(u64, f64)> {
+    let (lowest, highest) = range.unwrap_or((1, 1));
+    let top = scale.apply(highest as f64, lowest);
+    let fitted = match yscale {
+        Some(s) => s,
+        None if top > 0.0 => height as f64 / top,
+        // nothing to fit, one unit of the scale fills the height
+        None => height as f64,
+    };
+    let needed = (top * fitted).ceil() as usize;
+    if yscale.is_some() && needed > height {
+        return Err(Error::Render(format!(
+            "Histogram would be {} pixels high, but only {} fit",
+            needed, height
+        )));
+    }
+    Ok((lowest, fitted))
+}
+
+/// Height of the image in pixels, so that its pixels are square when `buckets` are stretched to
+/// an element of `width`×`height`.
+pub fn square_pixels(buckets: usize, width: f64, height: f64) -> Option<usize> {
+    if width > 0.0 && height > 0.0 {
+        Some((height * buckets as f64 / width).round().max(1.0) as usize)
+    } else {
+        None
+    }
+}
+
+pub fn hex(color: Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.0, color.1, color.2)
+}
+
+/// Fill the whole height of the buckets in which no capture was running with `color`, in an
+/// RGBA image of `width`×`height` pixels.
+pub fn paint_gaps(image: &mut [u8], width: usize, height: usize, uncovered: &[bool], color: Rgb) {
+    for (i, _) in uncovered.iter().enumerate().filter(|&(_, &u)| u) {
+        for y in 0..height {
+            let index = (i + width * y) * 4;
+            image[index..index + 3].copy_from_slice(&[color.0, color.1, color.2]);
         }
     }
+}
 
-    fn png_uncached(&self, path: PathBuf) -> Result<PathBuf, &str> {
-        let log_base = 10.0;
-        let log_min_display = 3.0; // minimum exponent where to start displaying
-        let width = if self.data.is_empty() { 100 } else { self.data[0].width };
-        let mut histo_data: Vec<i64> = vec![0; width];
-        for d in &self.data {
-            let data_cached: Vec<u64> = d.data_cached();
-            for i in 0..histo_data.len() {
-                histo_data[i] += data_cached[i] as i64;
-            }
+/// One rectangle of `color` over the whole `height` for every run of buckets in which no capture
+/// was running.
+pub fn gap_rects(g: &mut Group, height: usize, uncovered: &[bool], color: Rgb) {
+    let mut i = 0;
+    while i < uncovered.len() {
+        if !uncovered[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < uncovered.len() && uncovered[i] {
+            i += 1;
         }
+        g.append(
+            Rectangle::new()
+                .set("x", start)
+                .set("y", 0)
+                .set("width", i - start)
+                .set("height", height)
+                .set("fill", hex(color)),
+        );
+    }
+}
 
-        // find out bucket with min/max value
-        let mut max_value: i64 = 0;
-        let mut min_value: i64 = i64::max_value();
-        for b in &histo_data {
-            if max_value < *b {
-                max_value = *b;
-            }
-            if min_value > *b && b > &0 {
-                min_value = *b;
-            }
+/// Write an RGBA image of `width`×`height` pixels.
+pub fn write_png(path: &std::path::Path, width: usize, height: usize, image: &[u8]) -> Result<()> {
+    let file = File::create(path).map_err(Error::io(path))?;
+    let w = &mut BufWriter::new(file);
+    let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+    encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+    let png_error = |e: png::EncodingError| Error::Cache(path.to_path_buf(), e.to_string());
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(image).map_err(png_error)
+}
+
+/// The time below an element of `width`×`height` and the values with their unit left of it, for
+/// an image `image_height` pixels high that shows `data` with `scale`, see `fit_scale`.
+#[allow(clippy::too_many_arguments)]
+pub fn axes_overlay(
+    data: &HistogramData,
+    scale: Scale,
+    lowest: u64,
+    yscale: f64,
+    image_height: usize,
+    time_zone: Tz,
+    width: f64,
+    height: f64,
+) -> Group {
+    let start = DateTime::<Utc>::from(data.start_time).with_timezone(&time_zone);
+    let end = DateTime::<Utc>::from(data.end_time).with_timezone(&time_zone);
+
+    let (unit, rate) = data.metric.unit();
+    let bucket = (end - start).num_milliseconds() as f64 / 1e3 / data.width as f64;
+    // from values of a bucket to values on the axis
+    let factor = if rate && bucket > 0.0 {
+        1.0 / bucket
+    } else {
+        1.0
+    };
+    let top = scale.invert(image_height as f64 / yscale, lowest) * factor;
+    let ticks = match scale {
+        Scale::Log => axes::log_ticks(scale.bottom(lowest) * factor, top),
+        Scale::Linear | Scale::Sqrt => axes::linear_ticks(top, 4),
+    };
+    // units of the template per pixel of the image
+    let pixel = height / image_height as f64;
+    let ticks: Vec<(f64, String)> = ticks
+        .into_iter()
+        .map(|v| {
+            let y = height - scale.apply(v / factor, lowest) * yscale * pixel;
+            (y, axes::si_label(v, unit))
+        })
+        .collect();
+    Group::new()
+        .add(axes::time_axis(&start, &end, width, height))
+        .add(axes::value_axis(&ticks, height))
+}
+
+/// The values of a histogram and how they are scaled, see `Histogram::fitted`.
+#[derive(Clone)]
+pub struct Fitted {
+    values: Vec<u64>,
+    /// buckets in which no capture was running
+    uncovered: Vec<bool>,
+    /// smallest value the scale shows
+    lowest: u64,
+    /// pixels per unit of the scale
+    yscale: f64,
+}
+
+pub struct Histogram {
+    pub scale: Scale,
+    /// pixels per unit of the scale, fitted to `height` if `None`
+    pub yscale: Option<f64>,
+    /// height of the image in pixels, see `fit`
+    pub height: usize,
+    pub data: HistogramData,
+    /// data of the other histograms that share the scale with this one, so they can be compared
+    pub peers: Vec<HistogramData>,
+    pub color: (u8, u8, u8),
+    /// background of the buckets in which no capture was running, so they don't look like
+    /// buckets without traffic, not marked if `None`
+    pub gap_color: Option<(u8, u8, u8)>,
+    /// draw axes around the element, see `overlay`
+    pub axes: bool,
+    /// where the days of the time axis begin
+    pub time_zone: Tz,
+    /// computed on first use, see `fitted`
+    pub fitted: Mutex<Option<Fitted>>,
+}
+
+impl Histogram {
+    fn width(&self) -> usize {
+        self.data.width
+    }
+
+    /// The values of the buckets, and the scale they are drawn with. Unless `yscale` is given,
+    /// the scale is chosen so that the largest value, of this histogram and its peers, fills the
+    /// whole height. Both the image and the axes need them, so they are only computed once.
+    fn fitted(&self, cache: &Cache) -> Result<Fitted> {
+        let mut fitted = self.fitted.lock().unwrap();
+        if fitted.is_none() {
+            *fitted = Some(self.fit_values(cache)?);
         }
+        Ok(fitted.clone().unwrap())
+    }
 
-        let height: usize = if self.do_log {
-            (((max_value as f64).log(log_base) - log_min_display) * self.yscale) as usize + 1
+    fn fit_values(&self, cache: &Cache) -> Result<Fitted> {
+        let metric = self.data.metric;
+        let values = self.data.data_cached(cache)?;
+        let uncovered = if self.gap_color.is_some() {
+            self.data.uncovered(&values)
         } else {
-            (max_value as f64 * self.yscale) as usize + 1
+            vec![false; values.buckets.len()]
         };
-
-        if max_value == 0 {
-            println!("No bucket has any values. This will be an empty histogram");
-        } else {
-            println!(
-                "Timeline will consist of {} buckets, with min {}, max {} packets",
+        let histo_data = values.buckets;
+        let mut range = value_range(&histo_data);
+        match range {
+            None => println!("No bucket has any values. This will be an empty histogram"),
+            Some((min, max)) => println!(
+                "Timeline will consist of {} buckets, with min {}, max {} {}",
                 histo_data.len(),
-                min_value,
-                max_value
-            );
-            assert!(
-                height <= 661,
-                format!("height is greater than 416, but {}", height)
-            );
+                min,
+                max,
+                metric
+            ),
+        }
+        for peer in &self.peers {
+            range = merge_ranges(range, value_range(&peer.data_cached(cache)?.buckets));
         }
-        let height = 661;
+        let (lowest, yscale) = fit_scale(self.scale, self.yscale, self.height, range)?;
+        Ok(Fitted {
+            values: histo_data,
+            uncovered,
+            lowest,
+            yscale,
+        })
+    }
 
+    /// Height of the bar of every bucket in pixels, whether no capture was running in it, and
+    /// the height of the whole image. Every bucket that has a value gets at least one pixel.
+    fn bars(&self, cache: &Cache) -> Result<(Vec<usize>, Vec<bool>, usize)> {
+        let Fitted {
+            values: histo_data,
+            uncovered,
+            lowest,
+            yscale,
+        } = self.fitted(cache)?;
+        let height = self.height;
         println!(
             "Histogram will have dimensions {}x{} and color {:?}",
-            width, height, self.color,
+            histo_data.len(),
+            height,
+            self.color,
         );
 
-        let mut histo: Vec<u8> = vec![255; 4 * width * height];
-        for i in 0..histo_data.len() {
-            // println!("{}", histo_data[i]);
-            // distance in pixels from botton to top of the bucket
-            let value: i64 = if self.do_log {
-                if histo_data[i] > 0 {
-                    (((histo_data[i] as f64).log(log_base) - log_min_display) * self.yscale) as i64
-                } else {
-                    continue;
+        let bars = histo_data
+            .iter()
+            .map(|&v| {
+                if v == 0 {
+                    return 0;
                 }
-            } else {
-                (histo_data[i] as f64 * self.yscale) as i64
-            };
-            if value < 0 {
-                continue;
+                // distance in pixels from botton to top of the bucket
+                let value = (self.scale.apply(v as f64, lowest) * yscale).ceil() as usize;
+                value.max(1).min(height)
+            })
+            .collect();
+        Ok((bars, uncovered, height))
+    }
+}
+
+impl CachablePNG for Histogram {
+    fn cache_key(&self) -> Option<CacheKey> {
+        // bump the version whenever the drawing changes
+        let mut key = CacheKey::new("histogram 21");
+        key.add("scale", &format!("{:?}", self.scale));
+        key.add("yscale", &format!("{:?}", self.yscale));
+        key.add("height", &self.height);
+        key.add("data", &self.data.cache_key().hex());
+        for f in &self.data.captures.files {
+            key.add_input("data_file", f);
+        }
+        for peer in &self.peers {
+            key.add("peer data", &peer.cache_key().hex());
+            for f in &peer.captures.files {
+                key.add_input("peer data_file", f);
             }
-            for y in 0..(value as usize) {
+        }
+        key.add("color", &format!("{:?}", self.color));
+        key.add("gap_color", &format!("{:?}", self.gap_color));
+        Some(key)
+    }
+
+    fn png_uncached(&self, cache: &Cache, path: PathBuf) -> Result<PathBuf> {
+        let (bars, uncovered, height) = self.bars(cache)?;
+        let width = bars.len();
+
+        let mut histo: Vec<u8> = vec![255; 4 * width * height];
+        if let Some(color) = self.gap_color {
+            paint_gaps(&mut histo, width, height, &uncovered, color);
+        }
+        for (i, value) in bars.into_iter().enumerate() {
+            for y in 0..value {
                 let index = (i + (width * (height - y - 1))) * 4;
-                histo[index + 0] = self.color.0;
+                histo[index] = self.color.0;
                 histo[index + 1] = self.color.1;
                 histo[index + 2] = self.color.2;
             }
         }
 
         println!("{:?}", &path);
-        let file = File::create(&path).unwrap();
-        let ref mut w = BufWriter::new(file);
+        write_png(&path, width, height, &histo)?;
+        Ok(path)
+    }
 
-        let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+    /// One path for all bars, so zooming into the print doesn't show pixels.
+    fn svg(&self, cache: &Cache) -> Result<Option<(Group, u32, u32)>> {
+        let (bars, uncovered, height) = self.bars(cache)?;
+        let width = bars.len();
+        let mut g = Group::new();
+        if let Some(color) = self.gap_color {
+            gap_rects(&mut g, height, &uncovered, color);
+        }
+        let mut data = Data::new();
+        let mut i = 0;
+        while i < width {
+            if bars[i] == 0 {
+                i += 1;
+                continue;
+            }
+            // trace the skyline of all neighbouring buckets that have a bar
+            data = data.move_to((i, height));
+            while i < width && bars[i] > 0 {
+                let mut run = 1;
+                while i + run < width && bars[i + run] == bars[i] {
+                    run += 1;
+                }
+                data = data
+                    .vertical_line_to(height - bars[i])
+                    .horizontal_line_to(i + run);
+                i += run;
+            }
+            data = data.vertical_line_to(height).close();
+        }
+        if bars.iter().all(|&b| b == 0) {
+            return Ok(Some((g, width as u32, height as u32)));
+        }
+        let path = Path::new()
+            .set("d", data)
+            .set("fill", hex(self.color))
+            .set("stroke", "none");
+        g.append(path);
+        Ok(Some((g, width as u32, height as u32)))
+    }
+
+    /// The height of the image is chosen so that its pixels are square, when it is stretched to
+    /// the width of the element.
+    fn fit(&mut self, width: f64, height: f64) {
+        if let Some(h) = square_pixels(self.width(), width, height) {
+            self.height = h;
+            *self.fitted.lock().unwrap() = None;
+        }
+    }
+
+    /// The time below the element and the values with their unit left of it.
+    fn overlay(&self, cache: &Cache, width: f64, height: f64) -> Result<Option<Group>> {
+        if !self.axes {
+            return Ok(None);
+        }
+        let Fitted { lowest, yscale, .. } = self.fitted(cache)?;
+        Ok(Some(axes_overlay(
+            &self.data,
+            self.scale,
+            lowest,
+            yscale,
+            self.height,
+            self.time_zone,
+            width,
+            height,
+        )))
+    }
 
-        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&histo).unwrap(); // Save
-        Ok(path.to_path_buf())
+    fn histogram_data(&self) -> Vec<&HistogramData> {
+        vec![&self.data]
     }
 }
 
-fn get_bucket(width: usize, first: SystemTime, last: SystemTime, t: SystemTime) -> Option<usize> {
+/// Where a packet goes in a histogram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Before,
+    Bucket(usize),
+    After,
+}
+
+/// The slot of a packet at `t`, if the time window from `first` to `last`, both included, is
+/// split into `width` buckets of the same length.
+fn get_bucket(width: usize, first: SystemTime, last: SystemTime, t: SystemTime) -> Slot {
     let part = match t.duration_since(first) {
-        Ok(r) => r.as_nanos() as f64,
-        Err(_) => return None,
+        Ok(r) => r.as_nanos(),
+        Err(_) => return Slot::Before,
     };
-    let whole = last.duration_since(first).unwrap().as_nanos() as f64;
-    let ratio = part / whole;
-    let res = (width as f64 * ratio) as usize;
-    if res >= width {
-        // println!("{}/{}: {} {}", part, whole, ratio, res);
-        Some(width - 1)
-    } else {
-        Some(res)
+    // the window ends one nanosecond after `last`
+    let whole = last
+        .duration_since(first)
+        .map(|d| d.as_nanos() + 1)
+        .unwrap_or(1);
+    if part >= whole {
+        return Slot::After;
+    }
+    // in integers, so no packet ends up in the neighbouring bucket by rounding
+    Slot::Bucket((part * width as u128 / whole) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::time::Duration;
+    use testing::scratch_dir;
+
+    fn at(secs: u64, nanos: u32) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(secs, nanos)
+    }
+
+    #[test]
+    fn buckets_of_whole_seconds() {
+        // 10 buckets of one second, the last one ends with 9.999999999
+        let (first, last) = (at(0, 0), at(9, 999_999_999));
+        let slot = |t| get_bucket(10, first, last, t);
+        assert_eq!(slot(at(0, 0)), Slot::Bucket(0));
+        assert_eq!(slot(at(0, 999_999_999)), Slot::Bucket(0));
+        assert_eq!(slot(at(1, 0)), Slot::Bucket(1));
+        assert_eq!(slot(at(9, 999_999_999)), Slot::Bucket(9));
+        assert_eq!(slot(at(10, 0)), Slot::After);
+    }
+
+    #[test]
+    fn outside_of_the_window() {
+        let (first, last) = (at(100, 0), at(199, 0));
+        assert_eq!(
+            get_bucket(4, first, last, at(99, 999_999_999)),
+            Slot::Before
+        );
+        assert_eq!(get_bucket(4, first, last, at(199, 1)), Slot::After);
+        assert_eq!(get_bucket(4, first, last, at(199, 0)), Slot::Bucket(3));
+    }
+
+    #[test]
+    fn window_of_one_nanosecond() {
+        let t = at(5, 0);
+        assert_eq!(get_bucket(1, t, t, t), Slot::Bucket(0));
+        assert_eq!(get_bucket(1, t, t, at(5, 1)), Slot::After);
+        // more buckets than nanoseconds, all in the first one
+        assert_eq!(get_bucket(3, t, t, t), Slot::Bucket(0));
+    }
+
+    #[test]
+    fn buckets_that_dont_divide_evenly() {
+        // 3 buckets over 10 ns, split at 10/3 and 20/3
+        let (first, last) = (at(0, 0), at(0, 9));
+        let slot = |n| get_bucket(3, first, last, at(0, n));
+        assert_eq!(slot(3), Slot::Bucket(0));
+        assert_eq!(slot(4), Slot::Bucket(1));
+        assert_eq!(slot(6), Slot::Bucket(1));
+        assert_eq!(slot(7), Slot::Bucket(2));
+        assert_eq!(slot(9), Slot::Bucket(2));
+    }
+
+    /// Packets of a capture with one DNS query, counted in 4 buckets over an hour before it.
+    fn packets_in(cache_dir: &Path) -> (Cache, HistogramData) {
+        let capture = Path::new(env!("CARGO_MANIFEST_DIR")).join("raw_data/dns-01.pcapng");
+        let data = HistogramData {
+            filter: None,
+            filter_description: None,
+            start_time: at(0, 0),
+            end_time: at(3599, 999_999_999),
+            width: 4,
+            metric: Metric::Packets,
+            captures: CaptureSet {
+                device: "test".to_string(),
+                files: vec![capture],
+                dedup_window: None,
+                max_silence: None,
+            },
+        };
+        (Cache::new(cache_dir.to_path_buf()), data)
+    }
+
+    /// Put values with `header` where the values of `data` belong.
+    fn write_vec(cache: &Cache, data: &HistogramData, header: &VecHeader) {
+        let values = Values {
+            buckets: vec![7; data.width],
+            before: 0,
+            after: 0,
+            covered: Vec::new(),
+        };
+        let mut xz = XzEncoder::new(File::create(data.cache_path(cache)).unwrap(), 6);
+        serialize_into(&mut xz, header).unwrap();
+        serialize_into(&mut xz, &values).unwrap();
+        xz.finish().unwrap();
+    }
+
+    fn computed_again(cache: &Cache, data: &HistogramData) {
+        assert!(!data.is_cached(cache));
+        assert!(data.read_cache(cache).is_err());
+        let values = data.data_cached(cache).unwrap();
+        assert_eq!(values.buckets, vec![0; 4]);
+        assert_eq!(values.after, 1);
+        assert!(data.is_cached(cache));
+        assert_eq!(data.read_cache(cache).unwrap().buckets, vec![0; 4]);
+    }
+
+    #[test]
+    fn cached_values_are_used() {
+        let (cache, data) = packets_in(&scratch_dir("vec-cached"));
+        write_vec(&cache, &data, &data.vec_header());
+        assert!(data.is_cached(&cache));
+        assert_eq!(data.data_cached(&cache).unwrap().buckets, vec![7; 4]);
+    }
+
+    #[test]
+    fn other_version_is_computed_again() {
+        let (cache, data) = packets_in(&scratch_dir("vec-version"));
+        let mut header = data.vec_header();
+        header.version -= 1;
+        write_vec(&cache, &data, &header);
+        computed_again(&cache, &data);
+    }
+
+    #[test]
+    fn other_parameters_are_computed_again() {
+        let (cache, data) = packets_in(&scratch_dir("vec-parameters"));
+        let mut other = data.clone();
+        other.metric = Metric::Bytes;
+        write_vec(&cache, &data, &other.vec_header());
+        computed_again(&cache, &data);
+        other = data.clone();
+        other.filter = Some("dns".to_string());
+        write_vec(&cache, &data, &other.vec_header());
+        computed_again(&cache, &data);
     }
 }
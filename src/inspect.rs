@@ -0,0 +1,82 @@
+/// Quick overview over a capture file, to find out what is in there before putting it on a
+/// poster.
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use capture;
+use error::Result;
+use filter::Filter;
+use packet::{dissect, TransportHeader};
+
+fn unix_time(t: SystemTime) -> String {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => format!("{}.{:09}", d.as_secs(), d.subsec_nanos()),
+        Err(_) => "before 1970".to_string(),
+    }
+}
+
+/// Print packet and byte counts, the covered time span and the protocol mix of a capture,
+/// optionally only of the packets matching `filter`.
+pub fn inspect(path: &Path, filter: Option<&str>) -> Result<()> {
+    let filter = match filter {
+        Some(f) => Filter::parse(f)?,
+        None => Filter::Any,
+    };
+    let mut pcap = capture::open(path)?;
+
+    let mut total: u64 = 0;
+    let mut count: u64 = 0;
+    let mut byte_sum: u64 = 0;
+    let mut first: Option<SystemTime> = None;
+    let mut last: Option<SystemTime> = None;
+    let mut link_types: BTreeMap<String, u64> = BTreeMap::new();
+    let mut protocols: BTreeMap<&str, u64> = BTreeMap::new();
+    while let Some(pkt) = pcap.next() {
+        let pkt = pkt.map_err(capture::capture_error(path))?;
+        total += 1;
+        let link_type = pkt.interface.map(|i| i.link_type);
+        let d = dissect(link_type, pkt.data);
+        if !filter.matches(&d) {
+            continue;
+        }
+        count += 1;
+        byte_sum += pkt.data.len() as u64;
+        if let Some(t) = pkt.timestamp {
+            first = Some(first.map_or(t, |f| f.min(t)));
+            last = Some(last.map_or(t, |l| l.max(t)));
+        }
+        *link_types
+            .entry(match link_type {
+                Some(l) => format!("{:?}", l),
+                None => "unknown".to_string(),
+            })
+            .or_insert(0) += 1;
+        let protocol = match (&d.ip, &d.transport) {
+            (Some(ip), Some(TransportHeader::Tcp { .. })) if ip.version == 4 => "IPv4/TCP",
+            (Some(_), Some(TransportHeader::Tcp { .. })) => "IPv6/TCP",
+            (Some(ip), Some(TransportHeader::Udp { .. })) if ip.version == 4 => "IPv4/UDP",
+            (Some(_), Some(TransportHeader::Udp { .. })) => "IPv6/UDP",
+            (Some(ip), None) if ip.version == 4 => "IPv4/other",
+            (Some(_), None) => "IPv6/other",
+            (None, _) => "other",
+        };
+        *protocols.entry(protocol).or_insert(0) += 1;
+    }
+
+    println!("{}", path.display());
+    println!("  {} of {} packets, {} bytes", count, total, byte_sum);
+    if let (Some(f), Some(l)) = (first, last) {
+        println!("  first packet at {}", unix_time(f));
+        println!("  last packet at  {}", unix_time(l));
+    }
+    println!("  link types:");
+    for (l, n) in &link_types {
+        println!("    {:<12} {}", l, n);
+    }
+    println!("  protocols:");
+    for (p, n) in &protocols {
+        println!("    {:<12} {}", p, n);
+    }
+    Ok(())
+}
@@ -1,139 +1,369 @@
+extern crate base64;
 extern crate bincode;
-extern crate csv;
+extern crate chrono;
+extern crate chrono_tz;
+extern crate clap;
+extern crate flate2;
 extern crate pcarp;
 extern crate png;
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
 extern crate svg;
+extern crate svg2pdf;
+extern crate toml;
+extern crate ttf_parser;
 extern crate xml;
 extern crate xz2;
 #[macro_use]
 extern crate serde_derive;
 
+mod axes;
 mod cachable;
+mod capture;
+mod capture_set;
+mod config;
 mod datagram;
+mod error;
+mod filter;
+mod flowtext;
 mod histogram;
+mod inspect;
+mod manifest;
+mod packet;
+mod parallel;
+mod pdf;
 mod plakat;
+mod scan;
+mod stacked;
 mod template;
+#[cfg(test)]
+mod testing;
 
-use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime};
+use std::path::PathBuf;
 
-use std::boxed::Box;
+use clap::{Args, Parser, Subcommand};
 
-use histogram::{Histogram, HistogramData};
+use config::PosterConfig;
+use error::{Error, Result};
+use plakat::ImageMode;
+use scan::{Rounding, Span};
 
-fn mk_histogram_data(
-    data_file: &'static Path,
-    filter: String,
-    filter_description: String,
-) -> Box<HistogramData> {
-    Box::new(HistogramData {
-        filter: Some(filter),
-        filter_description: Some(filter_description),
-        // Monday, January 14, 2019 12:00:00 AM GMT+01:00
-        start_time: SystemTime::UNIX_EPOCH + Duration::new(1547420400, 0),
-        // Sunday, January 20, 2019 11:59:59 PM GMT+01:00
-        end_time: SystemTime::UNIX_EPOCH + Duration::new(1548025199, 999999999),
-        width: 6000,
-        do_pps: false,
-        data_file: data_file,
-    })
+/// Generate the images of the poster from network dumps and fill them into the template.
+#[derive(Parser)]
+#[command(name = "plakat")]
+struct Cli {
+    /// Poster description
+    #[arg(short, long, global = true, default_value = "./poster.toml")]
+    config: PathBuf,
+    /// Template SVG, overrides `template` from the config
+    #[arg(long, global = true)]
+    template: Option<PathBuf>,
+    /// Where to write the filled template, overrides `output` from the config
+    #[arg(short, long, global = true)]
+    output: Option<PathBuf>,
+    /// Directory for cached images and histogram data, overrides `cache_dir` from the config
+    #[arg(long, global = true)]
+    cache_dir: Option<PathBuf>,
+    /// Directory with the packet dumps, overrides `raw_data` from the config
+    #[arg(long, global = true)]
+    raw_data: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn mk_histogram(
-    data_files: &Vec<&'static Path>,
-    filter: String,
-    filter_description: String,
-    color_str: String,
-) -> Histogram {
-    histogram::Histogram {
-        do_log: true,
-        // yscale: 0.0000001, // nolog
-        yscale: 100.0, // with log
-        color: (
-            u8::from_str_radix(&color_str[..2], 16).unwrap(),
-            u8::from_str_radix(&color_str[2..4], 16).unwrap(),
-            u8::from_str_radix(&color_str[4..6], 16).unwrap(),
-        ),
-        data: {
-            let mut v: Vec<Box<HistogramData>> = Vec::new();
-            for p in data_files {
-                v.push(mk_histogram_data(
-                    p,
-                    filter.clone(),
-                    filter_description.clone(),
+#[derive(Subcommand)]
+enum Command {
+    /// Fill the template with the generated images (the default)
+    Render(RenderArgs),
+    /// Look at or clean up the cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Print an overview of a capture file
+    Inspect {
+        pcap: PathBuf,
+        /// Only look at packets matching this display filter
+        #[arg(short, long)]
+        filter: Option<String>,
+    },
+    /// Find the first and last packet of the captures of all devices, and print the timeline
+    /// they span
+    Scan {
+        /// Widen the timeline to whole days or weeks
+        #[arg(short, long)]
+        round: Option<Rounding>,
+        /// Number of captures to read at the same time, one per CPU core by default
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+}
+
+#[derive(Args, Default)]
+struct RenderArgs {
+    /// Recompute all images and histogram data, even if they are cached
+    #[arg(short, long)]
+    force: bool,
+    /// Embed the images into the output instead of linking to the cache
+    #[arg(long)]
+    inline: bool,
+    /// Draw histograms and datagrams as vector graphics instead of PNGs
+    #[arg(long)]
+    vector: bool,
+    /// Also render the output to this PDF, overrides `pdf.with_text` from the config
+    #[arg(long)]
+    pdf: Option<PathBuf>,
+    /// Also render the output to this PDF with all text converted to paths, overrides
+    /// `pdf.text_as_paths` from the config
+    #[arg(long)]
+    pdf_outlined: Option<PathBuf>,
+    /// Number of elements and data files to work on at the same time, one per CPU core by
+    /// default
+    #[arg(short, long)]
+    jobs: Option<usize>,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// List all cached images and histogram data
+    List {
+        /// Also show the parameters and input files of every entry
+        #[arg(short, long)]
+        long: bool,
+    },
+    /// Check that the entries are complete and their input files didn't change
+    Verify,
+    /// Remove all entries that the current config doesn't use
+    Gc {
+        /// Only list what would be removed
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Remove all cached images and histogram data
+    Clear,
+}
+
+fn load_config(cli: &Cli) -> Result<PosterConfig> {
+    let mut config = PosterConfig::from_file(&cli.config)?;
+    if let Some(ref t) = cli.template {
+        config.template = t.clone();
+    }
+    if let Some(ref o) = cli.output {
+        config.output = o.clone();
+    }
+    if let Some(ref c) = cli.cache_dir {
+        config.cache_dir = c.clone();
+    }
+    if let Some(ref r) = cli.raw_data {
+        config.raw_data = r.clone();
+    }
+    Ok(config)
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    match cli.command {
+        None => render(cli, &RenderArgs::default()),
+        Some(Command::Render(ref args)) => render(cli, args),
+        Some(Command::Cache { ref command }) => cache_command(cli, command),
+        Some(Command::Inspect {
+            ref pcap,
+            ref filter,
+        }) => inspect::inspect(pcap, filter.as_ref().map(|f| f.as_str())),
+        Some(Command::Scan { round, jobs }) => scan_command(cli, round, jobs),
+    }
+}
+
+fn scan_command(cli: &Cli, round: Option<Rounding>, jobs: Option<usize>) -> Result<()> {
+    let config = load_config(cli)?;
+    let tz = config.timeline.time_zone()?;
+    let cache = cachable::Cache::new(config.cache_dir.clone());
+    let captures = config.captures();
+    let jobs = jobs.unwrap_or_else(parallel::default_jobs).max(1);
+    let spans = scan::scan_all(&cache, &captures, jobs)
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    cache.save_manifest()?;
+    let time = |t: std::time::SystemTime| {
+        chrono::DateTime::<chrono::Utc>::from(t)
+            .with_timezone(&tz)
+            .to_rfc3339_opts(chrono::SecondsFormat::AutoSi, false)
+    };
+    for (path, span) in captures.iter().zip(&spans) {
+        match *span {
+            Some(ref s) => println!(
+                "{}: {} packets from {} to {}",
+                path.display(),
+                s.packets,
+                time(s.first.time),
+                time(s.last.time)
+            ),
+            None => println!("{}: no packets", path.display()),
+        }
+    }
+    for device in &config.devices {
+        let covered = device
+            .data_files
+            .iter()
+            .filter_map(|f| captures.iter().position(|c| c == f))
+            .filter_map(|i| spans[i].as_ref())
+            .map(|s| (s.first.time, s.last.time))
+            .collect();
+        for (from, to) in capture_set::gaps(covered) {
+            println!(
+                "{}: no capture from {} to {}",
+                device.name,
+                time(from),
+                time(to)
+            );
+        }
+    }
+    let span = match Span::all(spans.into_iter().flatten()) {
+        Some(span) => span,
+        None => {
+            return Err(Error::Config(
+                "The captures of the devices have no packets".to_string(),
+            ))
+        }
+    };
+    println!(
+        "First packet: frame {} of {}",
+        span.first.frame,
+        span.first.path.display()
+    );
+    println!(
+        "Last packet: frame {} of {}",
+        span.last.frame,
+        span.last.path.display()
+    );
+    let (start, end) = match round {
+        Some(round) => round.widen(span.first.time, span.last.time, tz),
+        None => (span.first.time, span.last.time),
+    };
+    println!();
+    println!("[timeline]");
+    println!("time_zone = \"{}\"", tz);
+    println!("start = \"{}\"", time(start));
+    println!("end = \"{}\"", time(end));
+    Ok(())
+}
+
+fn cache_command(cli: &Cli, command: &CacheCommand) -> Result<()> {
+    let config = load_config(cli)?;
+    // only the garbage collection needs to know the elements
+    let cache = &cachable::Cache::new(config.cache_dir.clone());
+    let dir = cache.dir.display().to_string();
+    let dir_error = Error::io(&cache.dir);
+    match *command {
+        CacheCommand::List { long } => {
+            let entries = cache.entries().map_err(dir_error)?;
+            for e in entries {
+                let size = std::fs::metadata(&e).map(|m| m.len()).unwrap_or(0);
+                let entry = match cache.manifest_entry(&e) {
+                    Some(entry) => entry,
+                    None => {
+                        println!("{:>12} {} (not in the manifest)", size, e.display());
+                        continue;
+                    }
+                };
+                let elements: Vec<&str> = entry.elements.iter().map(|s| s.as_str()).collect();
+                println!(
+                    "{:>12} {} {} [{}]",
+                    size,
+                    e.display(),
+                    entry.kind,
+                    elements.join(", ")
+                );
+                if long {
+                    println!("             created {}", entry.created);
+                    for (name, value) in &entry.parameters {
+                        println!("             {} = {}", name, value);
+                    }
+                    for i in &entry.inputs {
+                        println!("             input {}", i.path.display());
+                    }
+                }
+            }
+        }
+        CacheCommand::Verify => {
+            let problems = cache.verify().map_err(dir_error)?;
+            for (path, problem) in &problems {
+                println!("{}: {}", path.display(), problem);
+            }
+            if !problems.is_empty() {
+                return Err(Error::Cache(
+                    cache.dir.clone(),
+                    format!("{} problems", problems.len()),
                 ));
             }
-            v
-        },
+            println!("{} is fine", dir);
+        }
+        CacheCommand::Gc { dry_run } => {
+            let p = config.to_plakat(cachable::Cache::new(config.cache_dir.clone()), None)?;
+            let unused = cache.gc(&p.cache_paths(), dry_run).map_err(dir_error)?;
+            for e in &unused {
+                println!("{}", e.display());
+            }
+            if dry_run {
+                println!("Would remove {} entries from {}", unused.len(), dir);
+            } else {
+                cache.save_manifest()?;
+                println!("Removed {} entries from {}", unused.len(), dir);
+            }
+        }
+        CacheCommand::Clear => {
+            let n = cache.clear().map_err(dir_error)?;
+            cache.save_manifest()?;
+            println!("Removed {} entries from {}", n, dir);
+        }
     }
+    Ok(())
+}
+
+fn render(cli: &Cli, args: &RenderArgs) -> Result<()> {
+    let jobs = args.jobs.unwrap_or_else(parallel::default_jobs).max(1);
+    let config = load_config(cli)?;
+    // before the captures are scanned, so their spans are recomputed too
+    let mut cache = cachable::Cache::new(config.cache_dir.clone());
+    if args.force {
+        cache.force_recomputation();
+    }
+    let mut p = config.to_plakat(cache, Some(jobs))?;
+    p.jobs = jobs;
+    if args.inline {
+        p.images = ImageMode::Inline;
+    }
+    if args.vector {
+        p.vector = true;
+    }
+    if let Some(ref pdf) = args.pdf {
+        p.pdf_path = Some(pdf.clone());
+    }
+    if let Some(ref pdf) = args.pdf_outlined {
+        p.pdf_outlined_path = Some(pdf.clone());
+    }
+    std::fs::create_dir_all(&p.cache.dir).map_err(Error::io(&p.cache.dir))?;
+    p.fill_histogram_cache();
+    p.report_coverage();
+    let failed = template::fill_generated_data_in_template(&p)?;
+    p.record_elements();
+    p.cache.save_manifest()?;
+    if let Some(ref pdf) = p.pdf_path {
+        pdf::export_pdf(&p.output_path, pdf, false)?;
+    }
+    if let Some(ref pdf) = p.pdf_outlined_path {
+        pdf::export_pdf(&p.output_path, pdf, true)?;
+    }
+    if !failed.is_empty() {
+        return Err(Error::Elements(failed));
+    }
+    Ok(())
 }
 
 fn main() {
-    // Network dumps of my laptop
-    let data_files_laptop = vec![
-        // Path::new("./path/to/your.pcapng"),
-    ];
-    // Network dumps of my smartphone
-    let data_files_swift = vec![
-        // Path::new("./path/to/your.pcapng"),
-    ];
-    let mut p = plakat::Plakat::new(7016, 9933);
-    let datags = vec![
-        ("first_packet", 0x66),
-        ("last_packet", 0x66),
-        ("dns-01", 0x00),
-        ("dns-02", 0x00),
-        ("dns-03", 0x00),
-        ("dns-04", 0x00),
-        ("dns-05", 0x00),
-        ("dns-06", 0x00),
-        ("dns-07", 0x00),
-        ("dns-08", 0x00),
-        ("dns-09", 0x00),
-        ("dns-10", 0x00),
-        ("dns-11", 0x00),
-        ("dns-12", 0x00),
-        ("dns-13", 0x00),
-        ("dns-14", 0x00),
-        ("dns-15", 0x00),
-        ("dns-16", 0x00),
-        ("dns-17", 0x00),
-        ("dns-18", 0x00),
-    ];
-    for (d, c) in datags {
-        let datag = datagram::Datagram {
-            packet_path: PathBuf::from(format!("./raw_data/{}.pcapng", d)),
-            gray_value: c,
-        };
-        p.elements
-            .insert(format!("datag_{}", d.to_string()), Box::new(datag));
-    }
-    let histos: Vec<(&str, &str, &str)> = vec![
-        // color, label, tcpdump filter
-        ("000000", "none", ""),
-        ("000000", "dns", "udp.port==53 || tcp.port==53"),
-        ("000000", "http", "tcp.port==80"),
-        ("000000", "https", "tcp.port==443"),
-        ("000000", "udp", "udp"),
-        ("000000", "dhcp", "udp.port==67 || udp.port==68"),
-        ("000000", "imap", "tcp.port==993"),
-        ("000000", "smtp", "tcp.port==587"),
-        ("000000", "ssh", "tcp.port==22"),
-    ];
-    for (color, name, filter) in histos {
-        for (suffix, data_file) in
-            vec![("laptop", &data_files_laptop), ("swift", &data_files_swift)]
-        {
-            p.elements.insert(
-                format!("histo_{}_{}", name.clone(), suffix),
-                Box::new(mk_histogram(
-                    &data_file,
-                    filter.to_string().clone(),
-                    name.to_string(),
-                    color.to_string(),
-                )),
-            );
-        }
+    let cli = Cli::parse();
+    if let Err(e) = run(&cli) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
-    template::fill_generated_data_in_template(&p);
 }
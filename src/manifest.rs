@@ -0,0 +1,82 @@
+/// Index of the cache directory, that remembers what every cached file was computed from and
+/// which elements of the poster use it. It lives next to the entries as `manifest.json`.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use error::{Error, Result};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// by file name of the entry
+    pub entries: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// e.g. "datagram" or "histogram data"
+    pub kind: String,
+    /// ids of the template elements that used the entry in the last run
+    pub elements: BTreeSet<String>,
+    /// everything the entry was computed from, in the order it went into the key
+    pub parameters: Vec<(String, String)>,
+    pub inputs: Vec<InputFile>,
+    /// unix time in seconds
+    pub created: u64,
+    /// size of the entry in bytes
+    pub size: u64,
+}
+
+/// An input file, as it was when an entry was computed from it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputFile {
+    pub path: PathBuf,
+    /// `None` if the file didn't exist
+    pub size: Option<u64>,
+    /// modification time in nanoseconds since the unix epoch
+    pub modified: Option<u64>,
+}
+
+impl InputFile {
+    /// Size and modification time of the file at `path` right now.
+    pub fn current(path: &Path) -> InputFile {
+        let meta = std::fs::metadata(path).ok();
+        InputFile {
+            path: path.to_path_buf(),
+            size: meta.as_ref().map(|m| m.len()),
+            modified: meta
+                .and_then(|m| m.modified().ok())
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos() as u64),
+        }
+    }
+
+    /// True if the file is still the same as when it was recorded.
+    pub fn unchanged(&self) -> bool {
+        *self == InputFile::current(&self.path)
+    }
+}
+
+impl Manifest {
+    /// The manifest in `dir`, or an empty one if there is none yet.
+    pub fn load(dir: &Path) -> Result<Manifest> {
+        let path = dir.join(MANIFEST_NAME);
+        let s = match std::fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Manifest::default())
+            }
+            Err(e) => return Err(Error::Io(path, e)),
+        };
+        serde_json::from_str(&s).map_err(|e| Error::Cache(path, e.to_string()))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(MANIFEST_NAME);
+        let s = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Cache(path.clone(), e.to_string()))?;
+        std::fs::write(&path, s).map_err(Error::io(&path))
+    }
+}
@@ -0,0 +1,598 @@
+/// Minimal dissection of captured frames, just deep enough to answer the questions our display
+/// filters ask (which IP version, which transport protocol, which ports).
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use pcarp::LinkType;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+pub const IP_PROTO_TCP: u8 = 6;
+pub const IP_PROTO_UDP: u8 = 17;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpHeader {
+    pub version: u8,
+    /// Protocol number of the payload (after skipping IPv6 extension headers).
+    pub protocol: u8,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportHeader {
+    Tcp { src_port: u16, dst_port: u16 },
+    Udp { src_port: u16, dst_port: u16 },
+}
+
+/// Everything we could make sense of in one frame. Layers we don't understand are left as `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dissection {
+    pub ethernet: bool,
+    pub ip: Option<IpHeader>,
+    pub transport: Option<TransportHeader>,
+    /// where in the frame the IP header starts
+    pub network_offset: Option<usize>,
+    /// where in the frame the TCP/UDP header starts
+    pub transport_offset: Option<usize>,
+}
+
+impl Dissection {
+    pub fn is_tcp(&self) -> bool {
+        matches!(self.transport, Some(TransportHeader::Tcp { .. }))
+    }
+
+    pub fn is_udp(&self) -> bool {
+        matches!(self.transport, Some(TransportHeader::Udp { .. }))
+    }
+
+    /// The conversation the frame belongs to, the same for both directions. Ports are 0 for
+    /// protocols other than TCP and UDP.
+    pub fn flow(&self) -> Option<Flow> {
+        let ip = self.ip.as_ref()?;
+        let (src_port, dst_port) = match self.transport {
+            Some(TransportHeader::Tcp { src_port, dst_port })
+            | Some(TransportHeader::Udp { src_port, dst_port }) => (src_port, dst_port),
+            None => (0, 0),
+        };
+        let src = (ip.src, src_port);
+        let dst = (ip.dst, dst_port);
+        Some(if src <= dst {
+            (ip.protocol, src, dst)
+        } else {
+            (ip.protocol, dst, src)
+        })
+    }
+
+    /// Source and destination address, as far as they belong to hosts out on the internet. The
+    /// capturing device itself and its neighbours have private, link-local or loopback
+    /// addresses, and multicast and broadcast addresses aren't hosts at all.
+    pub fn remote_hosts(&self) -> Vec<IpAddr> {
+        match self.ip {
+            Some(ref ip) => [ip.src, ip.dst]
+                .iter()
+                .cloned()
+                .filter(|a| !is_local(a))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Protocol number and address and port of both ends of a conversation, the smaller end first.
+pub type Flow = (u8, (IpAddr, u16), (IpAddr, u16));
+
+fn is_local(addr: &IpAddr) -> bool {
+    match *addr {
+        IpAddr::V4(a) => {
+            a.is_private()
+                || a.is_link_local()
+                || a.is_loopback()
+                || a.is_multicast()
+                || a.is_broadcast()
+                || a.is_unspecified()
+        }
+        IpAddr::V6(a) => {
+            a.is_unique_local()
+                || a.is_unicast_link_local()
+                || a.is_loopback()
+                || a.is_multicast()
+                || a.is_unspecified()
+        }
+    }
+}
+
+fn be16(data: &[u8], offset: usize) -> Option<u16> {
+    if data.len() < offset + 2 {
+        return None;
+    }
+    Some(u16::from(data[offset]) << 8 | u16::from(data[offset + 1]))
+}
+
+/// Dissect a frame as captured on an interface with the given link type. Captures without
+/// interface information (simple packet blocks) are assumed to be Ethernet.
+pub fn dissect(link_type: Option<LinkType>, data: &[u8]) -> Dissection {
+    let mut res = Dissection::default();
+    // offset of the network layer and its ethertype, if the link layer tells us
+    let (offset, ethertype) = match link_type.unwrap_or(LinkType::ETHERNET) {
+        LinkType::ETHERNET => {
+            res.ethernet = true;
+            let mut offset = 12;
+            let mut ethertype = match be16(data, offset) {
+                Some(t) => t,
+                None => return res,
+            };
+            // 802.1Q tags, possibly stacked
+            while ethertype == ETHERTYPE_VLAN {
+                offset += 4;
+                ethertype = match be16(data, offset) {
+                    Some(t) => t,
+                    None => return res,
+                };
+            }
+            (offset + 2, Some(ethertype))
+        }
+        LinkType::LINUX_SLL => (16, be16(data, 14)),
+        // 4 byte address family in host byte order, the IP version nibble is more reliable
+        LinkType::NULL | LinkType::LOOP => (4, None),
+        LinkType::RAW => (0, None),
+        _ => return res,
+    };
+    if data.len() <= offset {
+        return res;
+    }
+    let version = match ethertype {
+        Some(ETHERTYPE_IPV4) => 4,
+        Some(ETHERTYPE_IPV6) => 6,
+        Some(_) => return res,
+        None => data[offset] >> 4,
+    };
+    let payload = match version {
+        4 => dissect_ipv4(&data[offset..], &mut res),
+        6 => dissect_ipv6(&data[offset..], &mut res),
+        _ => None,
+    };
+    if res.ip.is_some() {
+        res.network_offset = Some(offset);
+    }
+    if let (Some(payload), Some(ip)) = (payload, &res.ip) {
+        res.transport = dissect_transport(ip.protocol, &data[offset + payload..]);
+        if res.transport.is_some() {
+            res.transport_offset = Some(offset + payload);
+        }
+    }
+    res
+}
+
+/// Fill in the IPv4 header and return where the payload starts, unless this is a non-first
+/// fragment.
+fn dissect_ipv4(data: &[u8], res: &mut Dissection) -> Option<usize> {
+    if data.len() < 20 {
+        return None;
+    }
+    let header_len = usize::from(data[0] & 0x0f) * 4;
+    res.ip = Some(IpHeader {
+        version: 4,
+        protocol: data[9],
+        src: IpAddr::V4(Ipv4Addr::new(data[12], data[13], data[14], data[15])),
+        dst: IpAddr::V4(Ipv4Addr::new(data[16], data[17], data[18], data[19])),
+    });
+    let fragment_offset = be16(data, 6)? & 0x1fff;
+    if header_len < 20 || data.len() < header_len || fragment_offset != 0 {
+        return None;
+    }
+    Some(header_len)
+}
+
+/// Fill in the IPv6 header, walking over extension headers, and return where the payload starts.
+fn dissect_ipv6(data: &[u8], res: &mut Dissection) -> Option<usize> {
+    if data.len() < 40 {
+        return None;
+    }
+    let addr = |o: usize| {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&data[o..o + 16]);
+        IpAddr::V6(Ipv6Addr::from(octets))
+    };
+    let mut next_header = data[6];
+    // the addresses are known even if the extension headers are truncated
+    res.ip = Some(IpHeader {
+        version: 6,
+        protocol: next_header,
+        src: addr(8),
+        dst: addr(24),
+    });
+    let mut offset = 40;
+    loop {
+        match next_header {
+            // hop-by-hop, routing, destination options
+            0 | 43 | 60 => {
+                if data.len() < offset + 2 {
+                    break;
+                }
+                next_header = data[offset];
+                offset += (usize::from(data[offset + 1]) + 1) * 8;
+            }
+            // fragment header, only the first fragment carries the transport header
+            44 => {
+                if data.len() < offset + 8 {
+                    break;
+                }
+                next_header = data[offset];
+                if be16(data, offset + 2)? & 0xfff8 != 0 {
+                    offset = data.len() + 1;
+                    break;
+                }
+                offset += 8;
+            }
+            _ => break,
+        }
+    }
+    if let Some(ref mut ip) = res.ip {
+        ip.protocol = next_header;
+    }
+    if offset > data.len() {
+        return None;
+    }
+    Some(offset)
+}
+
+fn dissect_transport(protocol: u8, data: &[u8]) -> Option<TransportHeader> {
+    let src_port = be16(data, 0)?;
+    let dst_port = be16(data, 2)?;
+    match protocol {
+        IP_PROTO_TCP => Some(TransportHeader::Tcp { src_port, dst_port }),
+        IP_PROTO_UDP => Some(TransportHeader::Udp { src_port, dst_port }),
+        _ => None,
+    }
+}
+
+/// What a part of a frame is, for coloring the bits of a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    MacAddress,
+    /// ethertype and everything else in link layer headers
+    LinkHeader,
+    IpAddress,
+    /// version, lengths, TTL, protocol and so on
+    IpHeader,
+    Port,
+    Checksum,
+    /// sequence numbers, flags, window and options of TCP, length of UDP
+    TransportHeader,
+    DnsHeader,
+    /// questions and answers
+    DnsRecords,
+    Payload,
+}
+
+impl FieldKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            FieldKind::MacAddress => "MAC address",
+            FieldKind::LinkHeader => "link layer header",
+            FieldKind::IpAddress => "IP address",
+            FieldKind::IpHeader => "IP header",
+            FieldKind::Port => "port",
+            FieldKind::Checksum => "checksum",
+            FieldKind::TransportHeader => "TCP/UDP header",
+            FieldKind::DnsHeader => "DNS header",
+            FieldKind::DnsRecords => "DNS records",
+            FieldKind::Payload => "payload",
+        }
+    }
+}
+
+/// Which byte of the frame belongs to what kind of field. Bytes of layers we can't dissect count
+/// as payload.
+pub fn field_kinds(link_type: Option<LinkType>, data: &[u8]) -> Vec<FieldKind> {
+    let d = dissect(link_type, data);
+    let mut res = vec![FieldKind::Payload; data.len()];
+    let mut mark = |from: usize, to: usize, kind: FieldKind| {
+        for k in res.iter_mut().take(to).skip(from) {
+            *k = kind;
+        }
+    };
+    let network = d.network_offset.unwrap_or(data.len());
+
+    // link layer
+    if d.ethernet {
+        mark(0, 12, FieldKind::MacAddress);
+        mark(12, network, FieldKind::LinkHeader);
+    } else if link_type == Some(LinkType::LINUX_SLL) {
+        mark(0, network, FieldKind::LinkHeader);
+        mark(6, 14, FieldKind::MacAddress);
+    } else {
+        mark(0, network, FieldKind::LinkHeader);
+    }
+
+    // network layer
+    let ip = match d.ip {
+        Some(ref ip) => ip,
+        None => return res,
+    };
+    let transport = d.transport_offset.unwrap_or(data.len());
+    mark(network, transport, FieldKind::IpHeader);
+    if ip.version == 4 {
+        mark(network + 10, network + 12, FieldKind::Checksum);
+        mark(network + 12, network + 20, FieldKind::IpAddress);
+    } else {
+        mark(network + 8, network + 40, FieldKind::IpAddress);
+    }
+
+    // transport layer
+    let (header_len, checksum, dns) = match d.transport {
+        Some(TransportHeader::Udp { src_port, dst_port }) => (
+            8,
+            6,
+            src_port == 53 || dst_port == 53 || src_port == 5353 || dst_port == 5353,
+        ),
+        Some(TransportHeader::Tcp { .. }) => {
+            let offset = data
+                .get(transport + 12)
+                .map_or(20, |o| usize::from(o >> 4) * 4);
+            (offset.max(20), 16, false)
+        }
+        None => return res,
+    };
+    let payload = (transport + header_len).min(data.len());
+    mark(transport, payload, FieldKind::TransportHeader);
+    mark(transport, transport + 4, FieldKind::Port);
+    mark(
+        transport + checksum,
+        transport + checksum + 2,
+        FieldKind::Checksum,
+    );
+
+    // application layer, only DNS for now
+    if dns {
+        mark(payload, payload + 12, FieldKind::DnsHeader);
+        mark(payload + 12, data.len(), FieldKind::DnsRecords);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: [u8; 4] = [192, 168, 1, 2];
+    const DST: [u8; 4] = [9, 9, 9, 9];
+
+    fn udp(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut res = Vec::new();
+        res.extend_from_slice(&src_port.to_be_bytes());
+        res.extend_from_slice(&dst_port.to_be_bytes());
+        res.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        res.extend_from_slice(&[0xab, 0xcd]);
+        res.extend_from_slice(payload);
+        res
+    }
+
+    /// IPv4 header without options, `fragment` are flags and fragment offset.
+    fn ipv4(protocol: u8, fragment: u16, payload: &[u8]) -> Vec<u8> {
+        let mut res = vec![0x45, 0, 0, 0, 0, 0];
+        res.extend_from_slice(&fragment.to_be_bytes());
+        res.extend_from_slice(&[64, protocol, 0x12, 0x34]);
+        res.extend_from_slice(&SRC);
+        res.extend_from_slice(&DST);
+        res.extend_from_slice(payload);
+        res
+    }
+
+    fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut res = vec![0x60, 0, 0, 0, 0, 0, next_header, 64];
+        res.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        res.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        res.extend_from_slice(payload);
+        res
+    }
+
+    /// Ethernet frame with `vlans` 802.1Q tags.
+    fn ethernet(vlans: usize, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut res = vec![0x11; 6];
+        res.extend_from_slice(&[0x22; 6]);
+        for _ in 0..vlans {
+            res.extend_from_slice(&[0x81, 0x00, 0x00, 0x01]);
+        }
+        res.extend_from_slice(&ethertype.to_be_bytes());
+        res.extend_from_slice(payload);
+        res
+    }
+
+    fn dns_query() -> Vec<u8> {
+        ethernet(0, ETHERTYPE_IPV4, &ipv4(17, 0, &udp(40000, 53, &[0; 20])))
+    }
+
+    #[test]
+    fn ethernet_ipv4_udp() {
+        let d = dissect(Some(LinkType::ETHERNET), &dns_query());
+        assert!(d.ethernet);
+        assert_eq!(
+            d.ip,
+            Some(IpHeader {
+                version: 4,
+                protocol: 17,
+                src: IpAddr::from(SRC),
+                dst: IpAddr::from(DST),
+            })
+        );
+        assert_eq!(
+            d.transport,
+            Some(TransportHeader::Udp {
+                src_port: 40000,
+                dst_port: 53
+            })
+        );
+        assert_eq!(d.network_offset, Some(14));
+        assert_eq!(d.transport_offset, Some(34));
+        // without interface information it is Ethernet, too
+        assert_eq!(dissect(None, &dns_query()), d);
+    }
+
+    #[test]
+    fn vlan_tags() {
+        let frame = ethernet(2, ETHERTYPE_IPV4, &ipv4(17, 0, &udp(1, 2, &[])));
+        let d = dissect(Some(LinkType::ETHERNET), &frame);
+        assert_eq!(d.network_offset, Some(22));
+        assert_eq!(d.transport_offset, Some(42));
+        assert!(d.is_udp());
+        // the tags are there, but the frame ends before the ethertype
+        let d = dissect(Some(LinkType::ETHERNET), &frame[..17]);
+        assert!(d.ethernet);
+        assert_eq!(d.ip, None);
+    }
+
+    #[test]
+    fn other_ethertypes() {
+        let arp = ethernet(0, 0x0806, &[0; 28]);
+        let d = dissect(Some(LinkType::ETHERNET), &arp);
+        assert!(d.ethernet);
+        assert_eq!(d.ip, None);
+        assert_eq!(d.network_offset, None);
+    }
+
+    #[test]
+    fn ipv4_fragments() {
+        // the first fragment, with "more fragments", has the UDP header
+        let first = ethernet(0, ETHERTYPE_IPV4, &ipv4(17, 0x2000, &udp(1, 2, &[0; 8])));
+        assert!(dissect(None, &first).is_udp());
+        // later ones don't, but the addresses are there
+        let later = ethernet(0, ETHERTYPE_IPV4, &ipv4(17, 0x0002, &[0; 16]));
+        let d = dissect(None, &later);
+        assert_eq!(d.transport, None);
+        assert_eq!(d.transport_offset, None);
+        assert_eq!(d.ip.unwrap().src, IpAddr::from(SRC));
+    }
+
+    #[test]
+    fn ipv6_extension_headers() {
+        // hop-by-hop options, 8 bytes, then a destination options header of 16 bytes
+        let mut ext = vec![60, 0, 0, 0, 0, 0, 0, 0];
+        ext.extend_from_slice(&[17, 1]);
+        ext.extend_from_slice(&[0; 14]);
+        ext.extend_from_slice(&udp(5353, 5353, &[]));
+        let frame = ethernet(0, ETHERTYPE_IPV6, &ipv6(0, &ext));
+        let d = dissect(None, &frame);
+        let ip = d.ip.clone().unwrap();
+        assert_eq!(ip.version, 6);
+        assert_eq!(ip.protocol, 17);
+        assert_eq!(d.network_offset, Some(14));
+        assert_eq!(d.transport_offset, Some(14 + 40 + 24));
+        assert!(d.is_udp());
+    }
+
+    #[test]
+    fn ipv6_fragments() {
+        // first fragment
+        let mut first = vec![17, 0, 0x00, 0x01, 0, 0, 0, 1];
+        first.extend_from_slice(&udp(1, 2, &[]));
+        let d = dissect(None, &ethernet(0, ETHERTYPE_IPV6, &ipv6(44, &first)));
+        assert!(d.is_udp());
+        // a later one
+        let later = [17, 0, 0x00, 0x09, 0, 0, 0, 1, 0, 0, 0, 0];
+        let d = dissect(None, &ethernet(0, ETHERTYPE_IPV6, &ipv6(44, &later)));
+        assert_eq!(d.transport, None);
+        assert!(d.ip.is_some());
+        // the fragment header is cut off, the addresses are still there
+        let d = dissect(None, &ethernet(0, ETHERTYPE_IPV6, &ipv6(44, &[17, 0, 0])));
+        assert_eq!(d.transport, None);
+        assert_eq!(d.ip.unwrap().src, "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn linux_cooked_capture() {
+        let mut frame = vec![0, 0, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&ipv4(17, 0, &udp(1, 2, &[])));
+        let d = dissect(Some(LinkType::LINUX_SLL), &frame);
+        assert!(!d.ethernet);
+        assert_eq!(d.network_offset, Some(16));
+        assert_eq!(d.transport_offset, Some(36));
+    }
+
+    #[test]
+    fn raw_ip() {
+        let d = dissect(Some(LinkType::RAW), &ipv4(17, 0, &udp(1, 2, &[])));
+        assert_eq!(d.network_offset, Some(0));
+        assert!(d.is_udp());
+        // the version is taken from the first nibble
+        let d = dissect(Some(LinkType::RAW), &ipv6(17, &udp(1, 2, &[])));
+        assert_eq!(d.ip.unwrap().version, 6);
+        assert_eq!(d.transport_offset, Some(40));
+        // neither IPv4 nor IPv6
+        assert_eq!(dissect(Some(LinkType::RAW), &[0x10; 40]).ip, None);
+    }
+
+    /// The kinds as runs of bytes, to compare them more easily.
+    fn runs(kinds: &[FieldKind]) -> Vec<(usize, usize, FieldKind)> {
+        let mut res: Vec<(usize, usize, FieldKind)> = Vec::new();
+        for (i, &k) in kinds.iter().enumerate() {
+            match res.last_mut() {
+                Some(&mut (_, ref mut to, kind)) if kind == k => *to = i + 1,
+                _ => res.push((i, i + 1, k)),
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn fields_of_a_dns_query() {
+        let frame = dns_query();
+        let kinds = field_kinds(Some(LinkType::ETHERNET), &frame);
+        assert_eq!(kinds.len(), frame.len());
+        assert_eq!(
+            runs(&kinds),
+            vec![
+                (0, 12, FieldKind::MacAddress),
+                (12, 14, FieldKind::LinkHeader),
+                (14, 24, FieldKind::IpHeader),
+                (24, 26, FieldKind::Checksum),
+                (26, 34, FieldKind::IpAddress),
+                (34, 38, FieldKind::Port),
+                (38, 40, FieldKind::TransportHeader),
+                (40, 42, FieldKind::Checksum),
+                (42, 54, FieldKind::DnsHeader),
+                (54, 62, FieldKind::DnsRecords),
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_of_other_udp() {
+        let frame = ethernet(0, ETHERTYPE_IPV4, &ipv4(17, 0, &udp(40000, 443, &[0; 4])));
+        let kinds = field_kinds(None, &frame);
+        assert_eq!(&kinds[42..], &[FieldKind::Payload; 4]);
+    }
+
+    #[test]
+    fn fields_of_truncated_frames() {
+        let frame = dns_query();
+        // cut in the UDP header, the fields that are there are still found
+        let kinds = field_kinds(Some(LinkType::ETHERNET), &frame[..39]);
+        assert_eq!(
+            runs(&kinds),
+            vec![
+                (0, 12, FieldKind::MacAddress),
+                (12, 14, FieldKind::LinkHeader),
+                (14, 24, FieldKind::IpHeader),
+                (24, 26, FieldKind::Checksum),
+                (26, 34, FieldKind::IpAddress),
+                (34, 38, FieldKind::Port),
+                (38, 39, FieldKind::TransportHeader),
+            ]
+        );
+        // cut in the IP header, which can't be dissected
+        let kinds = field_kinds(Some(LinkType::ETHERNET), &frame[..30]);
+        assert_eq!(
+            runs(&kinds),
+            vec![
+                (0, 12, FieldKind::MacAddress),
+                (12, 30, FieldKind::LinkHeader),
+            ]
+        );
+        assert!(field_kinds(Some(LinkType::ETHERNET), &[]).is_empty());
+    }
+}
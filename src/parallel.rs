@@ -0,0 +1,57 @@
+/// Independent pieces of work, like the elements of the poster, spread over several threads.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Number of threads to use if nothing else is asked for: one per CPU core.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Apply `f` to every item on up to `jobs` threads, and return the results in the order of the
+/// items. Whenever an item is done, the progress is printed as `what [done/total] name`, with the
+/// `name` of the item.
+pub fn map<T, R>(
+    jobs: usize,
+    what: &str,
+    items: &[T],
+    name: impl Fn(&T) -> String + Sync,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    let work = || loop {
+        let i = next.fetch_add(1, Ordering::SeqCst);
+        let item = match items.get(i) {
+            Some(item) => item,
+            None => break,
+        };
+        let res = f(item);
+        results.lock().unwrap()[i] = Some(res);
+        let done = done.fetch_add(1, Ordering::SeqCst) + 1;
+        println!("{} [{}/{}] {}", what, done, items.len(), name(item));
+    };
+    let threads = jobs.max(1).min(items.len());
+    if threads <= 1 {
+        work();
+    } else {
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(work);
+            }
+        });
+    }
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every item is done once all threads finished"))
+        .collect()
+}
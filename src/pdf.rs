@@ -0,0 +1,66 @@
+/// Turn the filled template into the PDFs that go to the print shop, without a detour through
+/// Inkscape.
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use error::{Error, Result};
+use flowtext;
+
+use svg2pdf::usvg;
+use svg2pdf::{ConversionOptions, PageOptions};
+
+/// Render the SVG at `svg` into a single page PDF at `pdf`. If `text_as_paths` is set, all text is
+/// converted into paths, so the PDF looks the same everywhere, even without the fonts. Flowed
+/// text is converted into plain text first, see `flowtext`.
+///
+/// Relative image links are resolved against the directory of the SVG, which is what
+/// `fill_generated_data_in_template` makes the links into the cache relative to.
+pub fn export_pdf(svg: &Path, pdf: &Path, text_as_paths: bool) -> Result<()> {
+    let data = std::fs::read_to_string(svg).map_err(Error::io(svg))?;
+    let svg_error = |e: String| Error::Pdf(pdf.to_path_buf(), format!("{}: {}", svg.display(), e));
+
+    let mut options = usvg::Options {
+        resources_dir: match svg.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => Some(dir.to_path_buf()),
+            _ => Some(".".into()),
+        },
+        ..usvg::Options::default()
+    };
+    options.fontdb_mut().load_system_fonts();
+    // usvg leaves out text without a font, without saying so
+    let missing: Arc<Mutex<BTreeSet<String>>> = Arc::default();
+    let select = usvg::FontResolver::default_font_selector();
+    let record = missing.clone();
+    options.font_resolver.select_font = Box::new(move |font, fontdb| {
+        let id = select(font, fontdb);
+        if id.is_none() {
+            let families: Vec<String> = font.families().iter().map(|f| f.to_string()).collect();
+            record.lock().unwrap().insert(families.join(", "));
+        }
+        id
+    });
+    let data = flowtext::flowed_to_text(&data, &options).map_err(svg_error)?;
+    let tree = usvg::Tree::from_str(&data, &options).map_err(|e| svg_error(e.to_string()))?;
+    let missing = missing.lock().unwrap();
+    if !missing.is_empty() {
+        let missing: Vec<&str> = missing.iter().map(|f| f.as_str()).collect();
+        return Err(svg_error(format!(
+            "No font found for {}, the text would be missing",
+            missing.join("; ")
+        )));
+    }
+
+    let conversion = ConversionOptions {
+        embed_text: !text_as_paths,
+        ..ConversionOptions::default()
+    };
+    // usvg converts physical units like mm with its dpi, so use the same one to get the
+    // original page size back
+    let page = PageOptions { dpi: options.dpi };
+    let bytes = svg2pdf::to_pdf(&tree, conversion, page)
+        .map_err(|e| Error::Pdf(pdf.to_path_buf(), e.to_string()))?;
+    std::fs::write(pdf, bytes).map_err(Error::io(pdf))?;
+    println!("Wrote {}", pdf.display());
+    Ok(())
+}
@@ -1,11 +1,40 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
-use std::path::Path;
+use std::path::PathBuf;
 
-use cachable::CachablePNG;
+use cachable::{CachablePNG, Cache};
+use error::{Error, Result};
+use histogram::HistogramData;
+use parallel;
+use scan;
+use template;
 
 use std::boxed::Box;
 
+/// How the generated images end up in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageMode {
+    /// `xlink:href` points to the image in the cache directory
+    Link,
+    /// the image is embedded as `data:` URI, so the output doesn't depend on the cache
+    Inline,
+}
+
+/// Takes the place of an element that can't even be set up, e.g. because its capture can't be
+/// read, so that only this element fails when the poster is drawn.
+pub struct Unavailable(pub String);
+
+impl CachablePNG for Unavailable {
+    fn png_cached(&self, _cache: &Cache) -> Result<PathBuf> {
+        Err(Error::Render(self.0.clone()))
+    }
+
+    fn png_uncached(&self, cache: &Cache, _path: PathBuf) -> Result<PathBuf> {
+        self.png_cached(cache)
+    }
+}
+
 #[allow(dead_code)]
 pub struct Plakat {
     width_pixels: u32,
@@ -14,9 +43,23 @@ pub struct Plakat {
     total_amount_of_packets: u64,
     pcap_files: Vec<File>,
 
-    pub elements: HashMap<String, Box<CachablePNG>>,
+    pub elements: HashMap<String, Box<dyn CachablePNG>>,
 
-    pub template_path: &'static Path,
+    pub template_path: PathBuf,
+    pub output_path: PathBuf,
+    pub cache: Cache,
+    pub images: ImageMode,
+    /// replace images by vector graphics where the element supports it
+    pub vector: bool,
+    /// PDF rendering of the output, with text as text
+    pub pdf_path: Option<PathBuf>,
+    /// PDF rendering of the output, with text converted to paths
+    pub pdf_outlined_path: Option<PathBuf>,
+    /// number of elements and data files that are worked on at the same time
+    pub jobs: usize,
+    /// captures whose time span is used, for the timeline or datagrams of their first or last
+    /// packet
+    pub scanned: Vec<PathBuf>,
 }
 
 impl Plakat {
@@ -29,7 +72,90 @@ impl Plakat {
             pcap_files: Vec::new(),
 
             elements: HashMap::new(),
-            template_path: &Path::new("./template.svg"),
+            template_path: PathBuf::from("./template.svg"),
+            output_path: PathBuf::from("./plakat.svg"),
+            cache: Cache::new(PathBuf::from("./cache")),
+            images: ImageMode::Link,
+            vector: false,
+            pdf_path: None,
+            pdf_outlined_path: None,
+            jobs: parallel::default_jobs(),
+            scanned: Vec::new(),
+        }
+    }
+
+    /// Let every element fit itself to the size of its `<image>` in the template.
+    pub fn fit_to_template(&mut self) -> Result<()> {
+        let sizes = template::image_sizes(&self.template_path)?;
+        for (id, e) in &mut self.elements {
+            if let Some(&(w, h)) = sizes.get(id) {
+                e.fit(w, h);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compute all histogram data of all elements that is not cached yet, reading every data
+    /// file only once for all filters.
+    pub fn fill_histogram_cache(&self) {
+        let data: Vec<&HistogramData> = self
+            .elements
+            .values()
+            .flat_map(|e| e.histogram_data())
+            .collect();
+        HistogramData::fill_cache(&self.cache, &data, self.jobs);
+    }
+
+    /// Print how much of the timeline the captures of every device cover, and how many packets
+    /// of every histogram data set are outside of it, once the histogram data is in the cache.
+    pub fn report_coverage(&self) {
+        let mut devices: BTreeMap<&str, &HistogramData> = BTreeMap::new();
+        // every data set only once, even if several histograms use it
+        let mut data: BTreeMap<PathBuf, &HistogramData> = BTreeMap::new();
+        for d in self.elements.values().flat_map(|e| e.histogram_data()) {
+            devices.entry(&d.captures.device).or_insert(d);
+            data.entry(d.cache_path(&self.cache)).or_insert(d);
+        }
+        for d in data.values() {
+            if let Err(e) = d.report_outside(&self.cache) {
+                println!("{}", e);
+            }
+        }
+        for (device, d) in devices {
+            match d.coverage(&self.cache) {
+                Ok(c) => println!(
+                    "The captures of {} cover {:.1}% of the timeline",
+                    device,
+                    c * 100.0
+                ),
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    /// All cache entries that the elements use.
+    pub fn cache_paths(&self) -> HashSet<PathBuf> {
+        self.elements
+            .values()
+            .flat_map(|e| e.cache_paths(&self.cache))
+            .chain(
+                self.scanned
+                    .iter()
+                    .map(|c| scan::cache_path(&self.cache, c)),
+            )
+            .collect()
+    }
+
+    /// Note in the manifest which elements use which cache entries.
+    pub fn record_elements(&self) {
+        let mut users: HashMap<PathBuf, BTreeSet<String>> = HashMap::new();
+        for (id, e) in &self.elements {
+            for path in e.cache_paths(&self.cache) {
+                users.entry(path).or_default().insert(id.clone());
+            }
+        }
+        for (path, ids) in users {
+            self.cache.set_elements(&path, ids);
         }
     }
 }
@@ -0,0 +1,292 @@
+/// Time span of a set of captures: when their first and last packet was captured, so the
+/// timeline of the poster doesn't have to be looked up by hand, and which packets these are, so
+/// they can be drawn as datagrams without extracting them into files of their own.
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use cachable::{Cache, CacheKey};
+use capture;
+use error::{Error, Result};
+use parallel;
+
+/// A packet, found by its time, and where it is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Found {
+    pub time: SystemTime,
+    pub path: PathBuf,
+    /// starting at 1, like in Wireshark and `PacketSelection`
+    pub frame: usize,
+}
+
+/// Earliest and latest packet of a capture, or of several.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Span {
+    pub first: Found,
+    pub last: Found,
+    /// packets with a timestamp
+    pub packets: u64,
+}
+
+impl Span {
+    /// The span of all of the captures together.
+    pub fn all<I: IntoIterator<Item = Span>>(spans: I) -> Option<Span> {
+        spans.into_iter().fold(None, |res, s| match res {
+            Some(r) => Some(r.merge(s)),
+            None => Some(s),
+        })
+    }
+
+    fn merge(self, other: Span) -> Span {
+        Span {
+            first: if other.first.time < self.first.time {
+                other.first
+            } else {
+                self.first
+            },
+            last: if other.last.time > self.last.time {
+                other.last
+            } else {
+                self.last
+            },
+            packets: self.packets + other.packets,
+        }
+    }
+}
+
+/// Read the whole capture at `path`. `None` if there is no packet with a timestamp in it.
+pub fn scan_uncached(path: &Path) -> Result<Option<Span>> {
+    let mut pcap = capture::open(path)?;
+    let mut span: Option<Span> = None;
+    let mut frame = 0;
+    while let Some(pkt) = pcap.next() {
+        let pkt = pkt.map_err(capture::capture_error(path))?;
+        frame += 1;
+        let time = match pkt.timestamp {
+            Some(t) => t,
+            None => continue,
+        };
+        let found = Found {
+            time,
+            path: path.to_path_buf(),
+            frame,
+        };
+        let this = Span {
+            first: found.clone(),
+            last: found,
+            packets: 1,
+        };
+        span = Some(match span {
+            Some(s) => s.merge(this),
+            None => this,
+        });
+    }
+    Ok(span)
+}
+
+fn cache_key(path: &Path) -> CacheKey {
+    let mut key = CacheKey::new("capture scan");
+    key.add_input("capture", path);
+    key
+}
+
+/// The span of the capture at `path`, if it is in the cache, without reading the capture.
+pub fn cached(cache: &Cache, path: &Path) -> Option<Option<Span>> {
+    let cache_path = cache_path(cache, path);
+    if !cache.is_fresh(&cache_path) {
+        return None;
+    }
+    let cached = File::open(&cache_path)
+        .map_err(|e| e.to_string())
+        .and_then(|f| serde_json::from_reader(BufReader::new(f)).map_err(|e| e.to_string()));
+    match cached {
+        Ok(span) => Some(span),
+        Err(e) => {
+            println!("{}: {}, scanning it again", cache_path.display(), e);
+            None
+        }
+    }
+}
+
+/// The span of the capture at `path`, from the cache if it is there. Reading a whole capture
+/// takes long, and the span is needed on every run that uses it.
+pub fn scan(cache: &Cache, path: &Path) -> Result<Option<Span>> {
+    if let Some(span) = cached(cache, path) {
+        return Ok(span);
+    }
+    let key = cache_key(path);
+    let cache_path = cache.path(&format!("{}.scan", key.hex()));
+    println!("Scanning {}", path.display());
+    let span = scan_uncached(path)?;
+    std::fs::create_dir_all(&cache.dir).map_err(Error::io(&cache.dir))?;
+    let file = File::create(&cache_path).map_err(Error::io(&cache_path))?;
+    serde_json::to_writer(BufWriter::new(file), &span)
+        .map_err(|e| Error::Cache(cache_path.clone(), e.to_string()))?;
+    cache.record(&cache_path, &key);
+    Ok(span)
+}
+
+/// Where the scan of the capture at `path` is cached.
+pub fn cache_path(cache: &Cache, path: &Path) -> PathBuf {
+    cache.path(&format!("{}.scan", cache_key(path).hex()))
+}
+
+/// The spans of all captures, `jobs` of them are read at the same time.
+pub fn scan_all(cache: &Cache, paths: &[PathBuf], jobs: usize) -> Vec<Result<Option<Span>>> {
+    parallel::map(
+        jobs,
+        "Scanned",
+        paths,
+        |p| p.display().to_string(),
+        |p| scan(cache, p),
+    )
+}
+
+/// Whole units of time that a span can be widened to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Rounding {
+    /// from midnight to midnight
+    Day,
+    /// from Monday to Sunday
+    Week,
+}
+
+impl Rounding {
+    /// The beginning of the day or week `t` is in, in the time zone `tz`.
+    fn floor(self, t: SystemTime, tz: Tz) -> SystemTime {
+        let local = DateTime::<Utc>::from(t).with_timezone(&tz).date_naive();
+        let date = match self {
+            Rounding::Day => local,
+            Rounding::Week => {
+                local - Duration::days(i64::from(local.weekday().num_days_from_monday()))
+            }
+        };
+        tz.from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+            .earliest()
+            // midnight may be skipped by summer time, then the day starts a bit later
+            .unwrap_or_else(|| tz.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()))
+            .into()
+    }
+
+    /// From the beginning of the day or week of `start` to the last moment of the day or week of
+    /// `end`.
+    pub fn widen(self, start: SystemTime, end: SystemTime, tz: Tz) -> (SystemTime, SystemTime) {
+        let length = match self {
+            Rounding::Day => Duration::days(1),
+            Rounding::Week => Duration::weeks(1),
+        };
+        let last = self.floor(end, tz);
+        // days with summer time changes aren't 24 hours long, so look for the next beginning a
+        // bit after the middle of the last day or week
+        let next = self.floor(
+            SystemTime::from(DateTime::<Utc>::from(last) + length + length / 2),
+            tz,
+        );
+        let end = next - std::time::Duration::from_nanos(1);
+        (self.floor(start, tz), end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> SystemTime {
+        DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    fn widen(round: Rounding, start: &str, end: &str) -> (SystemTime, SystemTime) {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        round.widen(time(start), time(end), tz)
+    }
+
+    const NANO: std::time::Duration = std::time::Duration::from_nanos(1);
+
+    #[test]
+    fn whole_days() {
+        assert_eq!(
+            widen(
+                Rounding::Day,
+                "2019-01-14T08:30:00+01:00",
+                "2019-01-15T23:59:00+01:00"
+            ),
+            (
+                time("2019-01-14T00:00:00+01:00"),
+                time("2019-01-16T00:00:00+01:00") - NANO
+            )
+        );
+        // a time exactly at midnight stays in its day
+        assert_eq!(
+            widen(
+                Rounding::Day,
+                "2019-01-14T00:00:00+01:00",
+                "2019-01-15T00:00:00+01:00"
+            ),
+            (
+                time("2019-01-14T00:00:00+01:00"),
+                time("2019-01-16T00:00:00+01:00") - NANO
+            )
+        );
+    }
+
+    #[test]
+    fn whole_weeks() {
+        // Wednesday to Friday of the same week
+        assert_eq!(
+            widen(
+                Rounding::Week,
+                "2019-01-16T12:00:00+01:00",
+                "2019-01-18T12:00:00+01:00"
+            ),
+            (
+                time("2019-01-14T00:00:00+01:00"),
+                time("2019-01-21T00:00:00+01:00") - NANO
+            )
+        );
+        // Sunday to Monday are two weeks
+        assert_eq!(
+            widen(
+                Rounding::Week,
+                "2019-01-20T23:00:00+01:00",
+                "2019-01-21T01:00:00+01:00"
+            ),
+            (
+                time("2019-01-14T00:00:00+01:00"),
+                time("2019-01-28T00:00:00+01:00") - NANO
+            )
+        );
+    }
+
+    #[test]
+    fn days_with_summer_time_changes() {
+        // 23 hours
+        assert_eq!(
+            widen(
+                Rounding::Day,
+                "2019-03-31T12:00:00+02:00",
+                "2019-03-31T12:00:00+02:00"
+            ),
+            (
+                time("2019-03-31T00:00:00+01:00"),
+                time("2019-04-01T00:00:00+02:00") - NANO
+            )
+        );
+        // 25 hours
+        assert_eq!(
+            widen(
+                Rounding::Day,
+                "2019-10-27T12:00:00+01:00",
+                "2019-10-27T23:30:00+01:00"
+            ),
+            (
+                time("2019-10-27T00:00:00+02:00"),
+                time("2019-10-28T00:00:00+01:00") - NANO
+            )
+        );
+    }
+}
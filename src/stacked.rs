@@ -0,0 +1,379 @@
+/// Several histograms of the same captures stacked on top of each other in one image, one layer
+/// per protocol, so the poster can show the mix of protocols over time in one panel.
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use svg::node::element::path::Data;
+use svg::node::element::{Group, Path};
+use svg::node::Node;
+
+use cachable::{CachableData, CachablePNG, Cache, CacheKey};
+use chrono_tz::Tz;
+use error::Result;
+use histogram::{self, HistogramData, Rgb, Scale};
+
+/// One protocol of a `StackedHistogram`. The filter of its data only matches the packets that
+/// none of the layers below matched, see `exclusive_filter`, so every packet is counted once.
+pub struct Layer {
+    pub color: Rgb,
+    pub data: HistogramData,
+}
+
+/// The layers are stacked in their order, the first one at the bottom. The whole bar of a bucket
+/// is as high as a histogram of the sum of all layers with the same scale, and split between the
+/// layers in proportion to their values, so with `Log` the share of a layer is still readable.
+pub struct StackedHistogram {
+    pub scale: Scale,
+    /// pixels per unit of the scale, fitted to `height` if `None`
+    pub yscale: Option<f64>,
+    /// height of the image in pixels, see `fit`
+    pub height: usize,
+    pub layers: Vec<Layer>,
+    /// layers of the other stacked histograms that share the scale with this one
+    pub peers: Vec<Vec<HistogramData>>,
+    /// background of the buckets in which no capture was running, not marked if `None`
+    pub gap_color: Option<Rgb>,
+    /// draw axes around the element, see `overlay`
+    pub axes: bool,
+    /// where the days of the time axis begin
+    pub time_zone: Tz,
+    /// computed on first use, see `segments`
+    pub stacking: Mutex<Option<Stacking>>,
+}
+
+/// The filter of a layer that only matches what all `filters` match, and none of the filters of
+/// the layers `below`. Empty filters match everything.
+pub fn exclusive_filter(filters: &[&str], below: &[&str]) -> String {
+    let parts: Vec<String> = filters
+        .iter()
+        .filter(|f| !f.is_empty())
+        .map(|f| format!("({})", f))
+        .chain(below.iter().map(|f| format!("!({})", f)))
+        .collect();
+    parts.join(" && ")
+}
+
+/// Bottom and top of every layer of every bucket in pixels, from the bottom of the image.
+pub type Segments = Vec<Vec<(usize, usize)>>;
+
+/// The segments of the layers, which buckets no capture was running in, and the scale, see
+/// `StackedHistogram::segments`.
+pub type Stacking = (Segments, Vec<bool>, u64, f64);
+
+/// Split a bar of `bar` pixels between the layers in proportion to their `values`, the first one
+/// at the bottom. In integers, so the layers meet without gaps and the last one ends at the top.
+fn stack(bar: usize, values: &[u64]) -> Vec<(usize, usize)> {
+    let total: u64 = values.iter().sum();
+    if total == 0 {
+        return vec![(0, 0); values.len()];
+    }
+    let mut below = 0;
+    values
+        .iter()
+        .map(|&v| {
+            let bottom = (bar as u128 * below as u128 / total as u128) as usize;
+            below += v;
+            let top = (bar as u128 * below as u128 / total as u128) as usize;
+            (bottom, top)
+        })
+        .collect()
+}
+
+impl StackedHistogram {
+    fn width(&self) -> usize {
+        self.layers.first().map(|l| l.data.width).unwrap_or(100)
+    }
+
+    /// The buckets of every layer.
+    fn layer_values(cache: &Cache, layers: &[&HistogramData]) -> Result<Vec<Vec<u64>>> {
+        layers
+            .iter()
+            .map(|d| d.data_cached(cache).map(|v| v.buckets))
+            .collect()
+    }
+
+    /// The segments of the layers, which buckets no capture was running in, and the scale, see
+    /// `histogram::fit_scale`. Both the image and the axes need them, so they are only computed
+    /// once, like `Histogram::fitted`.
+    fn segments(&self, cache: &Cache) -> Result<Stacking> {
+        let mut stacking = self.stacking.lock().unwrap();
+        if stacking.is_none() {
+            *stacking = Some(self.stack_values(cache)?);
+        }
+        Ok(stacking.clone().unwrap())
+    }
+
+    fn stack_values(&self, cache: &Cache) -> Result<Stacking> {
+        let data: Vec<&HistogramData> = self.layers.iter().map(|l| &l.data).collect();
+        let values = StackedHistogram::layer_values(cache, &data)?;
+        let sum = |values: &[Vec<u64>]| -> Vec<u64> {
+            (0..self.width())
+                .map(|i| values.iter().map(|v| v[i]).sum())
+                .collect()
+        };
+        let total = sum(&values);
+        let mut range = histogram::value_range(&total);
+        for peer in &self.peers {
+            let peer: Vec<&HistogramData> = peer.iter().collect();
+            let peer_total = sum(&StackedHistogram::layer_values(cache, &peer)?);
+            range = histogram::merge_ranges(range, histogram::value_range(&peer_total));
+        }
+        let (lowest, yscale) = histogram::fit_scale(self.scale, self.yscale, self.height, range)?;
+        let uncovered = match (self.gap_color, data.first()) {
+            (Some(_), Some(d)) => d.uncovered(&d.data_cached(cache)?),
+            _ => vec![false; self.width()],
+        };
+        println!(
+            "Stacked histogram will have dimensions {}x{} and {} layers",
+            self.width(),
+            self.height,
+            self.layers.len()
+        );
+        let segments = (0..self.width())
+            .map(|i| {
+                let bucket: Vec<u64> = values.iter().map(|v| v[i]).collect();
+                if total[i] == 0 {
+                    return stack(0, &bucket);
+                }
+                let bar = (self.scale.apply(total[i] as f64, lowest) * yscale).ceil() as usize;
+                stack(bar.max(1).min(self.height), &bucket)
+            })
+            .collect();
+        Ok((segments, uncovered, lowest, yscale))
+    }
+}
+
+impl CachablePNG for StackedHistogram {
+    fn cache_key(&self) -> Option<CacheKey> {
+        // bump the version whenever the drawing changes
+        let mut key = CacheKey::new("stacked histogram 1");
+        key.add("scale", &format!("{:?}", self.scale));
+        key.add("yscale", &format!("{:?}", self.yscale));
+        key.add("height", &self.height);
+        for l in &self.layers {
+            key.add("layer", &l.data.cache_key().hex());
+            key.add("color", &format!("{:?}", l.color));
+        }
+        // all layers read the same captures
+        if let Some(l) = self.layers.first() {
+            for f in &l.data.captures.files {
+                key.add_input("data_file", f);
+            }
+        }
+        for peer in &self.peers {
+            for d in peer {
+                key.add("peer data", &d.cache_key().hex());
+            }
+            if let Some(d) = peer.first() {
+                for f in &d.captures.files {
+                    key.add_input("peer data_file", f);
+                }
+            }
+        }
+        key.add("gap_color", &format!("{:?}", self.gap_color));
+        Some(key)
+    }
+
+    fn png_uncached(&self, cache: &Cache, path: PathBuf) -> Result<PathBuf> {
+        let (segments, uncovered, _, _) = self.segments(cache)?;
+        let (width, height) = (self.width(), self.height);
+        let mut image: Vec<u8> = vec![255; 4 * width * height];
+        if let Some(color) = self.gap_color {
+            histogram::paint_gaps(&mut image, width, height, &uncovered, color);
+        }
+        for (i, bucket) in segments.iter().enumerate() {
+            for (&(bottom, top), l) in bucket.iter().zip(&self.layers) {
+                for y in bottom..top {
+                    let index = (i + width * (height - y - 1)) * 4;
+                    image[index..index + 3].copy_from_slice(&[l.color.0, l.color.1, l.color.2]);
+                }
+            }
+        }
+        println!("{:?}", &path);
+        histogram::write_png(&path, width, height, &image)?;
+        Ok(path)
+    }
+
+    /// One path per layer, with one rectangle for every run of buckets in which the layer has
+    /// the same bottom and top.
+    fn svg(&self, cache: &Cache) -> Result<Option<(Group, u32, u32)>> {
+        let (segments, uncovered, _, _) = self.segments(cache)?;
+        let (width, height) = (self.width(), self.height);
+        let mut g = Group::new();
+        if let Some(color) = self.gap_color {
+            histogram::gap_rects(&mut g, height, &uncovered, color);
+        }
+        for (k, l) in self.layers.iter().enumerate() {
+            let mut data = Data::new();
+            let mut empty = true;
+            let mut i = 0;
+            while i < width {
+                let (bottom, top) = segments[i][k];
+                let mut run = 1;
+                while i + run < width && segments[i + run][k] == (bottom, top) {
+                    run += 1;
+                }
+                if top > bottom {
+                    data = data
+                        .move_to((i, height - bottom))
+                        .vertical_line_to(height - top)
+                        .horizontal_line_to(i + run)
+                        .vertical_line_to(height - bottom)
+                        .close();
+                    empty = false;
+                }
+                i += run;
+            }
+            if !empty {
+                g.append(
+                    Path::new()
+                        .set("d", data)
+                        .set("fill", histogram::hex(l.color))
+                        .set("stroke", "none"),
+                );
+            }
+        }
+        Ok(Some((g, width as u32, height as u32)))
+    }
+
+    /// Square pixels, like `Histogram::fit`.
+    fn fit(&mut self, width: f64, height: f64) {
+        if let Some(h) = histogram::square_pixels(self.width(), width, height) {
+            self.height = h;
+            *self.stacking.lock().unwrap() = None;
+        }
+    }
+
+    /// The time below the element and the values of the sum of all layers left of it.
+    fn overlay(&self, cache: &Cache, width: f64, height: f64) -> Result<Option<Group>> {
+        let first = match self.layers.first() {
+            Some(l) if self.axes => &l.data,
+            _ => return Ok(None),
+        };
+        let (_, _, lowest, yscale) = self.segments(cache)?;
+        Ok(Some(histogram::axes_overlay(
+            first,
+            self.scale,
+            lowest,
+            yscale,
+            self.height,
+            self.time_zone,
+            width,
+            height,
+        )))
+    }
+
+    fn histogram_data(&self) -> Vec<&HistogramData> {
+        self.layers.iter().map(|l| &l.data).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capture_set::CaptureSet;
+    use histogram::Metric;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use testing::scratch_dir;
+
+    #[test]
+    fn exclusive_filters() {
+        assert_eq!(exclusive_filter(&["udp"], &[]), "(udp)");
+        assert_eq!(
+            exclusive_filter(&["ip", "tcp"], &["port == 80", "port == 443"]),
+            "(ip) && (tcp) && !(port == 80) && !(port == 443)"
+        );
+        // the filter of the whole stack may be empty
+        assert_eq!(
+            exclusive_filter(&["", "dns"], &["ipv6"]),
+            "(dns) && !(ipv6)"
+        );
+        assert_eq!(exclusive_filter(&[""], &["dns", "udp"]), "!(dns) && !(udp)");
+        assert_eq!(exclusive_filter(&[""], &[]), "");
+        // the parentheses keep the operators of a filter together
+        assert_eq!(
+            exclusive_filter(&["tcp || udp"], &["dns || ntp"]),
+            "(tcp || udp) && !(dns || ntp)"
+        );
+    }
+
+    #[test]
+    fn stacking() {
+        assert_eq!(stack(10, &[1, 1]), vec![(0, 5), (5, 10)]);
+        assert_eq!(stack(10, &[3, 0, 7]), vec![(0, 3), (3, 3), (3, 10)]);
+        // rounded down, but without gaps and up to the top
+        assert_eq!(stack(10, &[1, 1, 1]), vec![(0, 3), (3, 6), (6, 10)]);
+        assert_eq!(stack(1, &[1, 1000]), vec![(0, 0), (0, 1)]);
+        assert_eq!(stack(0, &[0, 0]), vec![(0, 0), (0, 0)]);
+        assert_eq!(stack(10, &[0, 0]), vec![(0, 0), (0, 0)]);
+        // values that don't fit into 64 bits when multiplied with the bar
+        assert_eq!(
+            stack(1000, &[u64::MAX / 2, u64::MAX / 2]),
+            vec![(0, 500), (500, 1000)]
+        );
+    }
+
+    /// The packets of a capture of DNS queries in 4 buckets, with UDP at the bottom and
+    /// everything else on top.
+    fn dns_stack() -> StackedHistogram {
+        let capture = Path::new(env!("CARGO_MANIFEST_DIR")).join("raw_data/dns.pcapng");
+        let captures = CaptureSet {
+            device: "test".to_string(),
+            files: vec![capture],
+            dedup_window: None,
+            max_silence: None,
+        };
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_549_918_345);
+        let layer = |filter: String, color| Layer {
+            color,
+            data: HistogramData {
+                filter: Some(filter),
+                filter_description: None,
+                start_time: start,
+                end_time: start + Duration::from_nanos(999_999_999),
+                width: 4,
+                metric: Metric::Packets,
+                captures: captures.clone(),
+            },
+        };
+        StackedHistogram {
+            scale: Scale::Linear,
+            yscale: None,
+            height: 10,
+            layers: vec![
+                layer(exclusive_filter(&["udp"], &[]), (255, 0, 0)),
+                layer(exclusive_filter(&[""], &["udp"]), (0, 0, 255)),
+            ],
+            peers: Vec::new(),
+            gap_color: None,
+            axes: false,
+            time_zone: chrono_tz::UTC,
+            stacking: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn segments_are_computed_once() {
+        let cache = Cache::new(scratch_dir("stacked"));
+        let mut stack = dns_stack();
+        let (segments, _, _, _) = stack.segments(&cache).unwrap();
+        assert_eq!(segments.len(), 4);
+        for bucket in &segments {
+            assert_eq!(bucket.len(), 2);
+            assert_eq!(bucket[0].1, bucket[1].0);
+            assert!(bucket[1].1 <= 10);
+        }
+        // the largest bar fills the height
+        assert_eq!(segments.iter().map(|b| b[1].1).max(), Some(10));
+        assert!(stack.stacking.lock().unwrap().is_some());
+        // from the memo, even without the data in the cache
+        let empty = Cache::new(scratch_dir("stacked-empty").join("missing"));
+        assert_eq!(stack.segments(&empty).unwrap().0, segments);
+        // another height needs other segments
+        stack.fit(4.0, 20.0);
+        assert!(stack.stacking.lock().unwrap().is_none());
+        let (segments, _, _, _) = stack.segments(&cache).unwrap();
+        assert_eq!(segments.iter().map(|b| b[1].1).max(), Some(20));
+    }
+}
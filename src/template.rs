@@ -1,9 +1,232 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::BufReader;
-use xml::reader::{EventReader, XmlEvent};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use xml::attribute::OwnedAttribute;
+use xml::name::OwnedName;
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
+use xml::writer::{EmitterConfig, EventWriter};
 
-use plakat::Plakat;
+use cachable::CachablePNG;
+use error::{Error, Result};
+use parallel;
+use plakat::{ImageMode, Plakat};
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+const SODIPODI_NS: &str = "http://sodipodi.sourceforge.net/DTD/sodipodi-0.dtd";
+
+fn is_href(a: &OwnedAttribute) -> bool {
+    a.name.local_name == "href"
+        && (a.name.namespace.as_deref() == Some(XLINK_NS)
+            || a.name.prefix.as_deref() == Some("xlink"))
+}
+
+/// Inkscape remembers the absolute path of linked images, which would point to the old image.
+fn is_absref(a: &OwnedAttribute) -> bool {
+    a.name.local_name == "absref"
+        && (a.name.namespace.as_deref() == Some(SODIPODI_NS)
+            || a.name.prefix.as_deref() == Some("sodipodi"))
+}
+
+/// The PNG file as `data:` URI, so it can be embedded into the SVG.
+fn data_uri(png: &Path) -> Result<String> {
+    let bytes = std::fs::read(png).map_err(Error::io(png))?;
+    Ok(format!("data:image/png;base64,{}", base64::encode(&bytes)))
+}
+
+/// `png` as seen from the directory of `output`, so the link works wherever the output is written.
+/// Absolute if there is no relative path, e.g. on another drive.
+fn link(png: &Path, output: &Path) -> String {
+    let dir = match output.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let (png, dir) = match (png.canonicalize(), dir.canonicalize()) {
+        (Ok(png), Ok(dir)) => (png, dir),
+        _ => return png.to_string_lossy().into_owned(),
+    };
+    let png_parts: Vec<Component> = png.components().collect();
+    let dir_parts: Vec<Component> = dir.components().collect();
+    let common = png_parts
+        .iter()
+        .zip(&dir_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return png.to_string_lossy().into_owned();
+    }
+    let mut res = PathBuf::new();
+    for _ in common..dir_parts.len() {
+        res.push("..");
+    }
+    for part in &png_parts[common..] {
+        res.push(part);
+    }
+    res.to_string_lossy().into_owned()
+}
+
+/// What takes the place of an `<image>` of the template.
+enum Replacement {
+    /// vector graphics, with its size
+    Group(Fragment, u32, u32),
+    /// the PNG and what the `xlink:href` has to be to show it
+    Image(PathBuf, String),
+}
+
+fn replacement(p: &Plakat, element: &dyn CachablePNG) -> Result<Replacement> {
+    if p.vector || element.vector_only() {
+        if let Some((group, w, h)) = element.svg(&p.cache)? {
+            return Ok(Replacement::Group(fragment(&group.to_string())?, w, h));
+        }
+    }
+    let png = element.png_cached(&p.cache)?;
+    let href = match p.images {
+        ImageMode::Link => link(&png, &p.output_path),
+        ImageMode::Inline => data_uri(&png)?,
+    };
+    Ok(Replacement::Image(png, href))
+}
+
+/// Width and height of every `<image>` of the template that has an id and a size.
+pub fn image_sizes(template: &Path) -> Result<HashMap<String, (f64, f64)>> {
+    let file = File::open(template).map_err(Error::io(template))?;
+    let parser = ParserConfig::new().create_reader(BufReader::new(file));
+    let mut res = HashMap::new();
+    for e in parser {
+        let e = e.map_err(|e| Error::Template(template.to_path_buf(), e.to_string()))?;
+        if let XmlEvent::StartElement {
+            ref name,
+            ref attributes,
+            ..
+        } = e
+        {
+            if name.local_name != "image" {
+                continue;
+            }
+            if let (Some(id), Some(size)) = (image_id(attributes), image_size(attributes)) {
+                res.insert(id, size);
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn number(attributes: &[OwnedAttribute], name: &str) -> Option<f64> {
+    attributes
+        .iter()
+        .find(|a| a.name.local_name == name && a.name.prefix.is_none())
+        .and_then(|a| a.value.parse::<f64>().ok())
+}
+
+fn image_size(attributes: &[OwnedAttribute]) -> Option<(f64, f64)> {
+    Some((number(attributes, "width")?, number(attributes, "height")?))
+}
+
+fn image_id(attributes: &[OwnedAttribute]) -> Option<String> {
+    attributes
+        .iter()
+        .find(|a| a.name.local_name == "id" && a.name.prefix.is_none())
+        .map(|a| a.value.clone())
+}
+
+/// Attributes of the group that takes the place of an `<image>`: everything that only makes sense
+/// for images is dropped, and the group is moved and scaled to cover the same area as the image,
+/// if the group has a size of `w`×`h`.
+fn group_attributes(image: &[OwnedAttribute], w: u32, h: u32) -> Vec<OwnedAttribute> {
+    let x = number(image, "x").unwrap_or(0.0);
+    let y = number(image, "y").unwrap_or(0.0);
+    let sx = number(image, "width").unwrap_or(f64::from(w)) / f64::from(w.max(1));
+    let sy = number(image, "height").unwrap_or(f64::from(h)) / f64::from(h.max(1));
+    let placement = format!("translate({},{}) scale({},{})", x, y, sx, sy);
+
+    let mut res: Vec<OwnedAttribute> = image
+        .iter()
+        .filter(|a| !is_href(a) && !is_absref(a))
+        .filter(|a| {
+            a.name.prefix.is_some()
+                || !["x", "y", "width", "height", "preserveAspectRatio"]
+                    .contains(&a.name.local_name.as_str())
+        })
+        .cloned()
+        .collect();
+    match res
+        .iter_mut()
+        .find(|a| a.name.local_name == "transform" && a.name.prefix.is_none())
+    {
+        Some(t) => t.value = format!("{} {}", t.value, placement),
+        None => res.push(OwnedAttribute::new(
+            OwnedName::local("transform"),
+            placement,
+        )),
+    }
+    res
+}
+
+/// Attributes of the group with the overlay of the element `id`: its origin is the top left
+/// corner of the `<image>`, in the coordinate system of the image.
+fn overlay_attributes(image: &[OwnedAttribute], id: &str) -> Vec<OwnedAttribute> {
+    let x = number(image, "x").unwrap_or(0.0);
+    let y = number(image, "y").unwrap_or(0.0);
+    let mut transform = format!("translate({},{})", x, y);
+    if let Some(t) = image
+        .iter()
+        .find(|a| a.name.local_name == "transform" && a.name.prefix.is_none())
+    {
+        transform = format!("{} {}", t.value, transform);
+    }
+    vec![
+        OwnedAttribute::new(OwnedName::local("id"), format!("{}_overlay", id)),
+        OwnedAttribute::new(OwnedName::local("transform"), transform),
+    ]
+}
+
+/// The events of an SVG snippet, to be copied into the output.
+type Fragment = Vec<XmlEvent>;
+
+/// Parse an SVG snippet, as generated by the `svg` crate. Its texts may come from the config, so
+/// it is checked before anything is written, and only its element fails if it is broken.
+fn fragment(snippet: &str) -> Result<Fragment> {
+    // wrap it, so that the elements end up in the SVG namespace
+    let wrapped = format!("<svg xmlns=\"{}\">{}</svg>", SVG_NS, snippet);
+    let mut res = Vec::new();
+    let mut depth = 0;
+    for e in EventReader::from_str(&wrapped) {
+        let e = e.map_err(|e| Error::Render(format!("Invalid SVG: {}", e)))?;
+        match e {
+            XmlEvent::StartElement { .. } => {
+                depth += 1;
+                if depth == 1 {
+                    continue;
+                }
+            }
+            XmlEvent::EndElement { .. } => {
+                depth -= 1;
+                if depth == 0 {
+                    continue;
+                }
+            }
+            XmlEvent::StartDocument { .. } | XmlEvent::EndDocument => continue,
+            _ => {}
+        }
+        res.push(e);
+    }
+    Ok(res)
+}
+
+/// Copy a parsed SVG snippet into the output.
+fn write_fragment<W: Write>(
+    writer: &mut EventWriter<W>,
+    fragment: &[XmlEvent],
+) -> xml::writer::Result<()> {
+    for e in fragment {
+        if let Some(e) = e.as_writer_event() {
+            writer.write(e)?;
+        }
+    }
+    Ok(())
+}
 
 /// Fill data in template.
 ///
@@ -14,75 +237,204 @@ use plakat::Plakat;
 /// - the SVG elements that are defined in code
 /// - new image paths
 ///
-pub fn fill_generated_data_in_template(p: &Plakat) {
-    let mut image_links_to_change: HashMap<String, (String, String)> = HashMap::new();
-
-    let file = File::open(p.template_path).unwrap();
+/// The template itself is only read, the result is written to `p.output_path`. Every `<image>`
+/// whose `id` is the name of one of `p.elements` gets its `xlink:href` pointed to the generated
+/// image (or the image itself, see `ImageMode`), everything else is copied as it is. With
+/// `p.vector`, elements that can draw themselves as SVG replace the `<image>` by a group instead.
+/// Elements that only exist as SVG, like the legend, always do. Overlays of the elements, like
+/// the axes of histograms, are added in a group right after the `<image>`.
+///
+/// The elements are drawn before the output is written, `p.jobs` of them at the same time.
+pub fn fill_generated_data_in_template(p: &Plakat) -> Result<Vec<(String, Error)>> {
+    let file = File::open(&p.template_path).map_err(Error::io(&p.template_path))?;
     let file = BufReader::new(file);
+    let template_error =
+        |e: xml::reader::Error| Error::Template(p.template_path.clone(), e.to_string());
+    let events: Vec<XmlEvent> = ParserConfig::new()
+        .ignore_comments(false)
+        .create_reader(file)
+        .into_iter()
+        .collect::<std::result::Result<_, _>>()
+        .map_err(template_error)?;
 
-    let parser = EventReader::new(file);
-    for e in parser {
-        match e {
-            Ok(XmlEvent::StartElement {
-                name, attributes, ..
-            }) => {
-                let xml::name::OwnedName { local_name, .. } = name;
-                // println!("Found local_name {}", local_name);
-                if local_name != "image" {
-                    continue;
+    // every element of the template only once, in the order of the template
+    let mut ids: Vec<String> = Vec::new();
+    let mut sizes: HashMap<String, (f64, f64)> = HashMap::new();
+    for e in &events {
+        if let XmlEvent::StartElement {
+            ref name,
+            ref attributes,
+            ..
+        } = *e
+        {
+            if name.local_name != "image" {
+                continue;
+            }
+            if let Some(id) = image_id(attributes) {
+                if p.elements.contains_key(&id) && !ids.contains(&id) {
+                    if let Some(size) = image_size(attributes) {
+                        sizes.insert(id.clone(), size);
+                    }
+                    ids.push(id);
                 }
-                let (id, xlink) = {
-                    let mut res_id: Option<String> = None;
-                    let mut res_xlink: Option<String> = None;
-                    for a in attributes {
-                        let xml::attribute::OwnedAttribute { name: n, value: v } = a;
-                        let xml::name::OwnedName {
-                            local_name: n_str,
-                            namespace: _,
-                            prefix,
-                        } = n;
-                        if n_str == "id" {
-                            res_id = Some(v);
-                        } else if prefix == Some("xlink".to_string()) && n_str == "href" {
-                            res_xlink = Some(v);
-                        }
+            }
+        }
+    }
+    let drawn = parallel::map(
+        p.jobs,
+        "Drawn",
+        &ids,
+        |id| id.clone(),
+        |id| {
+            let element = &*p.elements[id];
+            let overlay = match sizes.get(id) {
+                Some(&(w, h)) => match element.overlay(&p.cache, w, h)? {
+                    Some(g) => Some(fragment(&g.to_string())?),
+                    None => None,
+                },
+                None => None,
+            };
+            Ok((replacement(p, element)?, overlay))
+        },
+    );
+    let mut replacements: HashMap<String, (Replacement, Option<Fragment>)> = HashMap::new();
+    let mut failed: Vec<(String, Error)> = Vec::new();
+    for (id, r) in ids.into_iter().zip(drawn) {
+        match r {
+            Ok(r) => {
+                replacements.insert(id, r);
+            }
+            Err(e) => {
+                println!("Could not draw {}: {}", id, e);
+                failed.push((id, e));
+            }
+        }
+    }
+
+    let out = File::create(&p.output_path).map_err(Error::io(&p.output_path))?;
+    let mut writer = EmitterConfig::new()
+        .perform_indent(false)
+        .create_writer(BufWriter::new(out));
+    let output_error =
+        |e: xml::writer::Error| Error::Template(p.output_path.clone(), e.to_string());
+
+    let mut changed: HashSet<String> = HashSet::new();
+    // name, attributes and content of the group that follows the current <image>
+    let mut overlay_after: Option<(OwnedName, Vec<OwnedAttribute>, Fragment)> = None;
+    for e in events {
+        match e {
+            XmlEvent::StartElement {
+                ref name,
+                ref attributes,
+                ref namespace,
+            } if name.local_name == "image" => {
+                let id = match image_id(attributes).filter(|id| p.elements.contains_key(id)) {
+                    Some(id) => id,
+                    None => {
+                        writer
+                            .write(e.as_writer_event().unwrap())
+                            .map_err(output_error)?;
+                        continue;
                     }
-                    if res_id.is_some() && res_xlink.is_some() {
-                        (res_id.unwrap(), res_xlink.unwrap())
-                    } else {
+                };
+                println!("Going to change image with id: {}", &id);
+                changed.insert(id.clone());
+                let (replacement, overlay) = match replacements.get(&id) {
+                    Some((r, overlay)) => (r, overlay),
+                    None => {
+                        // it failed, keep the image of the template, so the rest of the poster
+                        // is fine
+                        writer
+                            .write(xml::writer::XmlEvent::StartElement {
+                                name: name.borrow(),
+                                attributes: Cow::Owned(
+                                    attributes.iter().map(|a| a.borrow()).collect(),
+                                ),
+                                namespace: Cow::Borrowed(namespace),
+                            })
+                            .map_err(output_error)?;
                         continue;
                     }
                 };
-                if p.elements.contains_key(&id) {
-                    println!("Going to change image with id: {}", &id);
-                    image_links_to_change.insert(
-                        id.clone(),
-                        (
-                            xlink,
-                            p.elements
-                                .get(&id)
-                                .unwrap()
-                                .png_cached()
-                                .unwrap()
-                                .to_str()
-                                .unwrap()
-                                .to_string(),
-                        ),
-                    );
+                let mut g = name.clone();
+                g.local_name = "g".to_string();
+                overlay_after = overlay
+                    .as_ref()
+                    .map(|o| (g.clone(), overlay_attributes(attributes, &id), o.clone()));
+                match *replacement {
+                    Replacement::Group(ref group, w, h) => {
+                        println!("Replacing image of {} with vector graphics", id);
+                        let attributes = group_attributes(attributes, w, h);
+                        writer
+                            .write(xml::writer::XmlEvent::StartElement {
+                                name: g.borrow(),
+                                attributes: Cow::Owned(
+                                    attributes.iter().map(|a| a.borrow()).collect(),
+                                ),
+                                namespace: Cow::Borrowed(namespace),
+                            })
+                            .map_err(output_error)?;
+                        write_fragment(&mut writer, group).map_err(output_error)?;
+                        // the end of the <image> becomes the end of the group
+                    }
+                    Replacement::Image(ref png, ref href) => {
+                        let mut attributes: Vec<OwnedAttribute> = attributes
+                            .iter()
+                            .filter(|a| !is_absref(a))
+                            .cloned()
+                            .collect();
+                        for a in attributes.iter_mut().filter(|a| is_href(a)) {
+                            println!("Replacing image of {} with {}", id, png.display());
+                            a.value = href.clone();
+                        }
+                        writer
+                            .write(xml::writer::XmlEvent::StartElement {
+                                name: name.borrow(),
+                                attributes: Cow::Owned(
+                                    attributes.iter().map(|a| a.borrow()).collect(),
+                                ),
+                                namespace: Cow::Borrowed(namespace),
+                            })
+                            .map_err(output_error)?;
+                    }
                 }
             }
-            Err(e) => {
-                println!("Error: {}", e);
-                break;
+            XmlEvent::EndElement { ref name } if name.local_name == "image" => {
+                // ends the <image>, or the group that took its place
+                writer
+                    .write(xml::writer::XmlEvent::EndElement { name: None })
+                    .map_err(output_error)?;
+                if let Some((g, attributes, overlay)) = overlay_after.take() {
+                    writer
+                        .write(xml::writer::XmlEvent::StartElement {
+                            name: g.borrow(),
+                            attributes: Cow::Owned(attributes.iter().map(|a| a.borrow()).collect()),
+                            namespace: Cow::Owned(xml::namespace::Namespace::empty()),
+                        })
+                        .map_err(output_error)?;
+                    write_fragment(&mut writer, &overlay).map_err(output_error)?;
+                    writer
+                        .write(xml::writer::XmlEvent::EndElement { name: None })
+                        .map_err(output_error)?;
+                }
+            }
+            e => {
+                if let Some(e) = e.as_writer_event() {
+                    writer.write(e).map_err(output_error)?;
+                }
             }
-            _ => {}
         }
     }
 
-    let mut template_string = std::fs::read_to_string("template.svg").unwrap();
-    for (_, (old, new)) in &image_links_to_change {
-        println!("Replacing {} with {} in template.svg", old, new);
-        template_string = template_string.replace(old, new);
+    let mut missing: Vec<&String> = p
+        .elements
+        .keys()
+        .filter(|k| !changed.contains(*k))
+        .collect();
+    missing.sort();
+    for id in missing {
+        println!("No image with id {} in {}", id, p.template_path.display());
     }
-    std::fs::write("template.svg", template_string).unwrap();
+    println!("Wrote {}", p.output_path.display());
+    Ok(failed)
 }
@@ -0,0 +1,45 @@
+/// Helpers for the tests: scratch directories and small captures written on the fly.
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// An empty directory for the test `name`, in the temporary directory of the system.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("plakat-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A pcapng capture with one interface of `link_type` and one packet per entry of `packets`,
+/// captured at the given unix time in microseconds.
+pub fn pcapng(link_type: u16, packets: &[(u64, &[u8])]) -> Vec<u8> {
+    let mut res = Vec::new();
+    let mut block = |kind: u32, body: &[u8]| {
+        let len = (12 + body.len()).div_ceil(4) * 4;
+        res.extend_from_slice(&kind.to_le_bytes());
+        res.extend_from_slice(&(len as u32).to_le_bytes());
+        res.extend_from_slice(body);
+        res.resize(res.len() + len - 12 - body.len(), 0);
+        res.extend_from_slice(&(len as u32).to_le_bytes());
+    };
+    // section header: byte-order magic, version 1.0, unknown section length
+    let mut shb = vec![0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0];
+    shb.extend_from_slice(&(-1i64).to_le_bytes());
+    block(0x0a0d_0d0a, &shb);
+    // interface description: link type, reserved, snap length
+    let mut idb = link_type.to_le_bytes().to_vec();
+    idb.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    block(1, &idb);
+    for &(time, data) in packets {
+        // enhanced packet: interface, timestamp, captured and original length
+        let mut epb = 0u32.to_le_bytes().to_vec();
+        epb.extend_from_slice(&((time >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(time as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(data);
+        block(6, &epb);
+    }
+    res
+}